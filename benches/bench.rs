@@ -2,10 +2,32 @@
 extern crate test;
 extern crate mqtt;
 
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use test::Bencher;
 
 use mqtt::*;
 
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
 #[bench]
 fn bench_decode_connect_packets(b: &mut Bencher) {
     let buf = b"\x10\x1D\x00\x04MQTT\x04\xC0\x00\x3C\x00\
@@ -171,3 +193,36 @@ fn bench_match_topic_tree(b: &mut Bencher) {
 
     b.iter(|| tree.match_topic(&t))
 }
+
+// Build a tree of the filters, returns it with the bytes it allocated.
+fn build_topic_tree(filters: &[String]) -> (TopicTree, usize) {
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let tree = TopicTree::build(filters.iter().map(|filter| filter.parse().unwrap()));
+
+    (tree, ALLOCATED.load(Ordering::SeqCst) - before)
+}
+
+#[bench]
+fn bench_topic_tree_memory(b: &mut Bencher) {
+    let filters = |site: &str, sensor: &str| {
+        (0..100_000)
+            .map(|n| format!("{}/{}/{}/temp", site, n, sensor))
+            .collect::<Vec<_>>()
+    };
+    let short = filters("site", "sensor");
+    let long = filters(&"site".repeat(256), &"sensor".repeat(256));
+
+    let (_, short_used) = build_topic_tree(&short);
+    let (tree, long_used) = build_topic_tree(&long);
+
+    // the level names shared by the filters are stored once, whatever their length
+    assert!(
+        long_used - short_used < 64 * 1024,
+        "{} more bytes for longer shared names",
+        long_used - short_used
+    );
+
+    let topic = long[0].parse().unwrap();
+
+    b.iter(|| tree.match_topic(&topic));
+}
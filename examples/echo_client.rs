@@ -0,0 +1,88 @@
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+extern crate clap;
+
+extern crate mqtt;
+
+use std::process::exit;
+use std::time::Duration;
+
+use clap::{Arg, App};
+
+use mqtt::{Message, QoS};
+use mqtt::client::ConnectOptions;
+use mqtt::client::blocking::Client;
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: &str = "1883";
+const DEFAULT_TOPIC: &str = "echo";
+
+fn echo(addr: (&str, u16), topic: &str, payload: &str) -> mqtt::Result<()> {
+    let mut client = Client::connect(
+        addr,
+        ConnectOptions {
+            keep_alive: 30,
+            ..Default::default()
+        },
+    )?;
+
+    client.subscribe(&[(topic, QoS::AtLeastOnce)])?;
+    client.publish(Message::new(topic, payload, QoS::AtLeastOnce))?;
+
+    match client.recv_timeout(Duration::from_secs(5))? {
+        Some(msg) => {
+            println!(
+                "{}: {}",
+                msg.topic,
+                String::from_utf8_lossy(&msg.payload)
+            )
+        }
+        None => warn!("no echo received"),
+    }
+
+    client.disconnect()
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let matches = App::new("Echo Client")
+        .version("1.0")
+        .author("Flier Lu <flier.lu@gmail.com>")
+        .arg(Arg::with_name("host")
+            .short("h")
+            .value_name("HOST")
+            .default_value(DEFAULT_HOST)
+            .help("connect to the host"))
+        .arg(Arg::with_name("port")
+            .short("p")
+            .value_name("PORT")
+            .default_value(DEFAULT_PORT)
+            .help("connect to the port"))
+        .arg(Arg::with_name("topic")
+            .short("t")
+            .value_name("TOPIC")
+            .default_value(DEFAULT_TOPIC)
+            .help("publish to the topic"))
+        .arg(Arg::with_name("message")
+            .value_name("MESSAGE")
+            .default_value("hello")
+            .help("message to publish"))
+        .get_matches();
+
+    let addr = (matches.value_of("host").unwrap(),
+                matches.value_of("port").unwrap().parse().unwrap());
+
+    if let Err(ref err) = echo(addr,
+                               matches.value_of("topic").unwrap(),
+                               matches.value_of("message").unwrap()) {
+        error!("error: {}", err);
+
+        for err in err.iter().skip(1) {
+            error!("caused by: {}", err);
+        }
+
+        exit(-1);
+    }
+}
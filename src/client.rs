@@ -1,451 +1,3370 @@
-use std::rc::Rc;
-use std::time::Duration;
+use std::cmp;
+use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
-use slab::Slab;
+use rand::{thread_rng, Rng};
 
 use error::*;
 use proto::*;
 use packet::*;
+use topic::{is_valid_topic_filter, is_valid_topic_name, Topic, TopicFilter, TopicTree};
 use transport::{self, Transport};
 
+use self::store::SessionStore;
+
+pub use self::delivery::{DeliveryFailure, DeliveryState, DeliveryToken};
+pub use self::endpoint::Endpoint;
+
+pub mod delivery;
+pub mod endpoint;
+pub mod store;
+pub mod blocking;
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+
+/// The options used to open a session, most of them sent in the CONNECT packet.
 #[derive(Debug, PartialEq, Clone)]
-enum Waiting<'a> {
-    PublishAck {
-        packet_id: PacketId,
-        msg: Rc<Message<'a>>,
-    },
-    PublishComplete { packet_id: PacketId },
-    SubscribeAck {
-        packet_id: PacketId,
-        topic_filters: &'a [(&'a str, QoS)],
-    },
-    UnsubscribeAck {
-        packet_id: PacketId,
-        topic_filters: &'a [&'a str],
-    },
+pub struct ConnectOptions {
+    pub protocol: Protocol,
+    pub client_id: ClientId,
+    pub clean_session: bool,
+    /// keep alive interval in seconds, `0` disables it.
+    pub keep_alive: u16,
+    /// how long to wait for a PINGRESP before the connection is considered dead,
+    /// defaults to the keep alive interval.
+    pub ping_timeout: Option<Duration>,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+    pub last_will: Option<Message>,
+    /// whether the server should retain the last will message once published.
+    pub will_retain: bool,
+    /// how to re-establish a lost connection, `None` disables reconnecting.
+    /// A client built on a transport reconnects with `Transport::reconnect`.
+    pub reconnect: Option<ReconnectPolicy>,
+    /// when a QoS 2 message received from the server is delivered.
+    pub delivery: DeliveryMode,
+    /// how unacknowledged messages are resent, `None` only resends them on reconnect.
+    pub retry: Option<RetryPolicy>,
+    /// how messages published while disconnected are held until the next connection.
+    pub offline_queue: OfflineQueue,
+    /// the most QoS 1 and QoS 2 messages waiting for acknowledgment, `None` is unlimited.
+    pub max_in_flight: Option<usize>,
 }
 
-pub trait Handler {
-    fn on_received_message(&mut self, msg: &Message);
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            protocol: Protocol::default(),
+            client_id: ClientId::new(),
+            clean_session: true,
+            keep_alive: 0,
+            ping_timeout: None,
+            username: None,
+            password: None,
+            last_will: None,
+            will_retain: false,
+            reconnect: None,
+            delivery: DeliveryMode::default(),
+            retry: None,
+            offline_queue: OfflineQueue::default(),
+            max_in_flight: None,
+        }
+    }
+}
 
-    fn on_subscribed_topic(&mut self, topics: &[(&str, SubscribeReturnCode)]);
+/// How messages published while disconnected are queued, and flushed in order
+/// once the next connection is accepted.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct OfflineQueue {
+    /// the most messages held in the queue.
+    pub max_messages: usize,
+    /// the most bytes of topic and payload held in the queue.
+    pub max_bytes: usize,
+    /// what happens to a message published while the queue is full.
+    pub overflow: OverflowPolicy,
+}
 
-    fn on_unsubscribed_topic(&mut self, topics: &[&str]);
+impl Default for OfflineQueue {
+    fn default() -> Self {
+        OfflineQueue {
+            max_messages: 1024,
+            max_bytes: 1024 * 1024,
+            overflow: OverflowPolicy::default(),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct Session<'a, H: 'a + Handler> {
-    handler: &'a mut H,
+/// What happens to a message published while the offline queue is full.
+///
+/// A dropped QoS 1 or QoS 2 message is reported with `Event::PublishFailed`.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum OverflowPolicy {
+    /// fail the new message with `QueueFull`.
+    #[default]
+    RejectNew,
+    /// drop the oldest messages to make room for the new one.
+    DropOldest,
+    /// drop the oldest QoS 0 messages to make room, then fail the new message.
+    DropQoS0First,
+}
 
-    // QoS 1 and QoS 2 messages which have been sent to the Server,
-    // but have not been completely acknowledged.
-    waiting_reply: Slab<Waiting<'a>>,
+/// How unacknowledged QoS 1 and QoS 2 messages are resent on a live connection.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RetryPolicy {
+    /// how long to wait for an acknowledgment before resending the packet.
+    pub interval: Duration,
+    /// give up on a message after this many retransmissions, `None` retries forever.
+    pub max_retries: Option<u32>,
 }
 
-impl<'a, H: Handler> Session<'a, H> {
-    pub fn new(handler: &'a mut H) -> Self {
-        Session {
-            handler: handler,
-            waiting_reply: Slab::with_capacity(256),
-        }
-    }
-
-    pub fn reset(&mut self) {
-        self.waiting_reply.clear();
-    }
-
-    pub fn connect(
-        &'a mut self,
-        client_id: &'a ClientId,
-        clean_session: bool,
-        keep_alive: u16,
-        auth: Option<(&'a str, &'a [u8])>,
-        last_will: Option<Rc<Message<'a>>>,
-    ) -> Packet<'a> {
-        Packet::Connect {
-            protocol: Default::default(),
-            clean_session: clean_session,
-            keep_alive: keep_alive,
-            last_will: last_will.map(|msg| {
-                LastWill {
-                    topic: msg.topic,
-                    message: msg.payload,
-                    qos: msg.qos,
-                    retain: false,
-                }
-            }),
-            client_id: client_id,
-            username: auth.map(|(username, _)| username),
-            password: auth.map(|(_, password)| password),
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            interval: Duration::from_secs(20),
+            max_retries: None,
         }
     }
+}
 
-    fn delivery_retry(&mut self) -> Vec<Packet<'a>> {
-        self.waiting_reply
-            .iter()
-            .map(|ref waiting| match *waiting {
-                &Waiting::PublishAck { packet_id, ref msg } => {
-                    Packet::Publish {
-                        dup: false,
-                        retain: false,
-                        qos: msg.qos,
-                        topic: msg.topic,
-                        packet_id: Some(packet_id),
-                        payload: msg.payload,
-                    }
+/// When a QoS 2 message received from the server is delivered.
+///
+/// Either way a message is delivered once, resent PUBLISH packets are dropped
+/// until the server releases the packet id with PUBREL.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum DeliveryMode {
+    /// deliver the message as soon as PUBLISH is received.
+    #[default]
+    OnPublish,
+    /// hold the message until the server sends PUBREL.
+    OnRelease,
+}
+
+/// How a lost connection is re-established, with an exponential backoff between attempts.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReconnectPolicy {
+    /// the delay before the first attempt, doubled after each failed attempt.
+    pub initial_delay: Duration,
+    /// the longest delay between two attempts.
+    pub max_delay: Duration,
+    /// the fraction of each delay, between `0.0` and `1.0`, which is randomly removed
+    /// so that many clients don't reconnect at once.
+    pub jitter: f64,
+    /// give up after this many consecutive failed attempts, `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Returns the delay before the attempt following `failures` failed ones.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let delay = 1u32
+            .checked_shl(failures)
+            .and_then(|factor| self.initial_delay.checked_mul(factor))
+            .map_or(self.max_delay, |delay| cmp::min(delay, self.max_delay));
+
+        if self.jitter > 0.0 {
+            delay.mul_f64(1.0 - self.jitter.min(1.0) * thread_rng().gen::<f64>())
+        } else {
+            delay
+        }
+    }
+}
+
+/// A packet queued by the session, waiting to be written by the IO layer.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Outgoing {
+    Connect(Box<ConnectOptions>),
+    Publish {
+        dup: bool,
+        retain: bool,
+        packet_id: Option<PacketId>,
+        msg: Message,
+    },
+    PublishAck(PacketId),
+    PublishReceived(PacketId),
+    PublishRelease(PacketId),
+    PublishComplete(PacketId),
+    Subscribe {
+        packet_id: PacketId,
+        topic_filters: Vec<(String, QoS)>,
+    },
+    Unsubscribe {
+        packet_id: PacketId,
+        topic_filters: Vec<String>,
+    },
+    PingRequest,
+    Disconnect,
+}
+
+impl Outgoing {
+    pub fn packet(&self) -> Packet<'_> {
+        match *self {
+            Outgoing::Connect(ref opts) => {
+                Packet::Connect {
+                    protocol: opts.protocol,
+                    clean_session: opts.clean_session,
+                    keep_alive: opts.keep_alive,
+                    last_will: opts.last_will.as_ref().map(|msg| {
+                        LastWill {
+                            topic: &msg.topic,
+                            message: &msg.payload,
+                            qos: msg.qos,
+                            retain: opts.will_retain,
+                        }
+                    }),
+                    client_id: &opts.client_id,
+                    username: opts.username.as_deref(),
+                    password: opts.password.as_deref(),
                 }
-                &Waiting::PublishComplete { packet_id } => {
-                    Packet::PublishRelease { packet_id: packet_id }
+            }
+            Outgoing::Publish {
+                dup,
+                retain,
+                packet_id,
+                ref msg,
+            } => {
+                Packet::Publish {
+                    dup,
+                    retain,
+                    qos: msg.qos,
+                    topic: &msg.topic,
+                    packet_id,
+                    payload: &msg.payload,
                 }
-                &Waiting::SubscribeAck {
+            }
+            Outgoing::PublishAck(packet_id) => Packet::PublishAck { packet_id },
+            Outgoing::PublishReceived(packet_id) => Packet::PublishReceived { packet_id },
+            Outgoing::PublishRelease(packet_id) => Packet::PublishRelease { packet_id },
+            Outgoing::PublishComplete(packet_id) => Packet::PublishComplete { packet_id },
+            Outgoing::Subscribe {
+                packet_id,
+                ref topic_filters,
+            } => {
+                Packet::Subscribe {
                     packet_id,
-                    ref topic_filters,
-                } => {
-                    Packet::Subscribe {
-                        packet_id: packet_id,
-                        topic_filters: From::from(*topic_filters),
-                    }
+                    topic_filters: topic_filters
+                        .iter()
+                        .map(|&(ref filter, qos)| (filter.as_str(), qos))
+                        .collect(),
                 }
-                &Waiting::UnsubscribeAck {
+            }
+            Outgoing::Unsubscribe {
+                packet_id,
+                ref topic_filters,
+            } => {
+                Packet::Unsubscribe {
                     packet_id,
-                    ref topic_filters,
-                } => {
-                    Packet::Unsubscribe {
-                        packet_id: packet_id,
-                        topic_filters: From::from(*topic_filters),
-                    }
+                    topic_filters: topic_filters.iter().map(|filter| filter.as_str()).collect(),
                 }
-            })
-            .collect()
+            }
+            Outgoing::PingRequest => Packet::PingRequest,
+            Outgoing::Disconnect => Packet::Disconnect,
+        }
     }
+}
 
-    pub fn ping(&mut self) -> Packet<'a> {
-        Packet::PingRequest
+/// Something that happened in the session, reported to the application.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Event {
+    /// the server accepted the connection.
+    Connected { session_present: bool },
+    /// the server refused the connection.
+    ConnectRefused(ConnectReturnCode),
+    /// an application message was received.
+    Message(Message),
+    /// a QoS 1 or QoS 2 message has been completely acknowledged.
+    Published(PacketId),
+    /// the server acknowledged a subscription.
+    Subscribed {
+        packet_id: PacketId,
+        status: Vec<(String, SubscribeReturnCode)>,
+    },
+    /// the server acknowledged an unsubscription.
+    Unsubscribed {
+        packet_id: PacketId,
+        topic_filters: Vec<String>,
+    },
+    /// a request was not acknowledged in time and has been abandoned.
+    Timeout(PacketId),
+    /// a message was abandoned, resent too many times by the retry policy,
+    /// dropped from the full offline queue or by a clean session.
+    PublishFailed(PacketId),
+    /// the IO layer should open a new connection and call `Session::reconnect`.
+    Reconnect { attempt: u32 },
+    /// the reconnect policy gave up after too many failed attempts.
+    ReconnectFailed { attempts: u32 },
+    /// the server answered a PINGREQ after `latency`.
+    Pong { latency: Duration },
+    /// the connection was closed.
+    Disconnected(DisconnectReason),
+}
+
+/// Why a session was disconnected.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DisconnectReason {
+    /// the client sent DISCONNECT.
+    Requested,
+    /// the underlying connection was closed or failed.
+    ConnectionLost,
+    /// the server did not answer a PINGREQ in time.
+    KeepAliveTimeout,
+}
+
+/// The connection state of a session.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum State {
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct InFlight {
+    packet: Outgoing,
+    // the first clock tick that saw the packet on the wire
+    since: Option<Instant>,
+    // the clock tick of the latest transmission
+    sent: Option<Instant>,
+    // retransmissions on the current connection
+    retries: u32,
+    // handed out by `poll_packet`, so the server may have seen it
+    written: bool,
+    // published while disconnected, waiting in the offline queue
+    queued: bool,
+}
+
+impl InFlight {
+    fn new(packet: Outgoing) -> Self {
+        InFlight {
+            packet,
+            since: None,
+            sent: None,
+            retries: 0,
+            written: false,
+            queued: false,
+        }
     }
 
-    pub fn disconnect(&mut self) -> Packet<'a> {
-        Packet::Disconnect
+    // Only PUBLISH and PUBREL, which carry a message, are resent on a live connection
+    // and saved in the session store.
+    fn is_message(&self) -> bool {
+        matches!(self.packet, Outgoing::Publish { .. } | Outgoing::PublishRelease(_))
     }
 
-    pub fn publish(&mut self, msg: Rc<Message<'a>>) -> Packet<'a> {
-        Packet::Publish {
-            dup: false,
-            retain: false,
-            qos: msg.qos,
-            topic: msg.topic,
-            packet_id: match msg.qos {
-                QoS::AtLeastOnce | QoS::ExactlyOnce => self.wait_reply(msg.clone()),
-                _ => None,
-            },
-            payload: msg.payload,
+    // Returns the packet to send again, flagged as a duplicate once the server may have seen it.
+    fn resend(&mut self) -> Outgoing {
+        if self.written {
+            if let Outgoing::Publish { ref mut dup, .. } = self.packet {
+                *dup = true;
+            }
         }
+
+        self.packet.clone()
     }
+}
 
-    fn wait_reply(&mut self, msg: Rc<Message<'a>>) -> Option<PacketId> {
-        if let Some(entry) = self.waiting_reply.vacant_entry() {
-            let packet_id = entry.index() as PacketId;
+/// Allocates packet identifiers for outgoing messages.
+///
+/// Identifiers cycle through `1..=65535`, skipping those still in flight,
+/// so that an id is not reused until its previous exchange has completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketIdAllocator {
+    next: PacketId,
+}
 
-            Some(entry
-                .insert(Waiting::PublishAck {
-                    packet_id: packet_id,
-                    msg: msg.clone(),
-                })
-                .index() as PacketId)
-        } else {
-            warn!("too many message waiting ack, downgrade to QoS level 0");
+impl Default for PacketIdAllocator {
+    fn default() -> Self {
+        PacketIdAllocator { next: 1 }
+    }
+}
 
-            None
-        }
+impl PacketIdAllocator {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn on_publish_ack(&mut self, packet_id: PacketId) -> Option<Packet<'a>> {
-        if let Some(_) = self.waiting_reply.remove(packet_id as usize) {
-            debug!("message {} acknowledged", packet_id);
-        } else {
-            warn!("unexpected packet id {}", packet_id)
+    /// Returns the next free packet id, or `PacketIdExhausted` if all 65535 ids are in flight.
+    pub fn allocate<F>(&mut self, in_flight: F) -> Result<PacketId>
+    where
+        F: Fn(PacketId) -> bool,
+    {
+        for _ in 0..PacketId::MAX {
+            let packet_id = self.next;
+
+            self.next = if packet_id == PacketId::MAX {
+                1
+            } else {
+                packet_id + 1
+            };
+
+            if !in_flight(packet_id) {
+                return Ok(packet_id);
+            }
         }
 
-        None
+        bail!(ErrorKind::PacketIdExhausted)
     }
+}
 
-    fn on_publish_received(&mut self, packet_id: PacketId) -> Option<Packet<'a>> {
-        if let Some(mut entry) = self.waiting_reply.entry(packet_id as usize) {
-            debug!("message {} received at server side", packet_id);
+pub trait Handler {
+    fn on_received_message(&mut self, msg: &Message);
 
-            entry.replace(Waiting::PublishComplete { packet_id: packet_id });
+    fn on_subscribed_topic(&mut self, topics: &[(&str, SubscribeReturnCode)]);
 
-            Some(Packet::PublishRelease { packet_id: packet_id })
-        } else {
-            warn!("unexpected packet id {}", packet_id);
+    fn on_unsubscribed_topic(&mut self, topics: &[&str]);
 
-            None
-        }
-    }
+    /// the server accepted the connection, resuming a previous session if `session_present`.
+    fn on_connected(&mut self, _session_present: bool) {}
 
-    fn on_publish_complete(&mut self, packet_id: PacketId) -> Option<Packet<'a>> {
-        if let Some(_) = self.waiting_reply.remove(packet_id as usize) {
-            debug!("message {} completed", packet_id);
-        } else {
-            warn!("unexpected packet id {}", packet_id)
-        }
+    /// the server refused the connection.
+    fn on_connect_refused(&mut self, _return_code: ConnectReturnCode) {}
 
-        None
-    }
+    /// the connection was closed.
+    fn on_disconnected(&mut self, _reason: DisconnectReason) {}
 
-    fn on_publish(
-        &mut self,
-        _dup: bool,
-        _retain: bool,
-        packet_id: Option<PacketId>,
-        msg: Rc<Message<'a>>,
-    ) -> Option<Packet<'a>> {
-        self.handler.on_received_message(&msg);
+    /// the server answered a PINGREQ after `latency`.
+    fn on_pong(&mut self, _latency: Duration) {}
 
-        packet_id.and_then(|packet_id| self.send_reply(packet_id, msg))
-    }
+    /// a QoS 1 or QoS 2 message has been completely acknowledged.
+    fn on_published(&mut self, _token: &DeliveryToken) {}
 
-    fn send_reply(&mut self, packet_id: PacketId, msg: Rc<Message<'a>>) -> Option<Packet<'a>> {
-        match msg.qos {
-            QoS::AtLeastOnce => Some(Packet::PublishAck { packet_id: packet_id }),
-            QoS::ExactlyOnce => Some(Packet::PublishReceived { packet_id: packet_id }),
-            _ => None,
-        }
-    }
+    /// a QoS 1 or QoS 2 message could not be delivered.
+    fn on_publish_failed(&mut self, _token: &DeliveryToken, _reason: DeliveryFailure) {}
+}
 
-    fn on_publish_release(&mut self, packet_id: PacketId) -> Option<Packet<'a>> {
-        Some(Packet::PublishComplete { packet_id: packet_id })
-    }
+/// The client side protocol state of an MQTT session.
+///
+/// The session performs no IO: received packets and clock ticks are fed in
+/// through `handle_packet` and `handle_tick`, and the packets to send and the
+/// events to report are drained with `poll_packet` and `poll_event`.
+#[derive(Debug)]
+pub struct Session {
+    state: State,
 
-    pub fn subscribe(&mut self, topic_filters: &'a [(&'a str, QoS)]) -> Option<Packet<'a>> {
-        if let Some(entry) = self.waiting_reply.vacant_entry() {
-            let packet_id = entry.index() as PacketId;
+    // QoS 1 and QoS 2 messages, subscriptions and unsubscriptions which have been sent
+    // to the Server, but have not been completely acknowledged.
+    waiting_reply: HashMap<PacketId, InFlight>,
 
-            entry.insert(Waiting::SubscribeAck {
-                packet_id: packet_id,
-                topic_filters: topic_filters,
-            });
+    packet_ids: PacketIdAllocator,
 
-            Some(Packet::Subscribe {
-                packet_id: packet_id,
-                topic_filters: From::from(topic_filters),
-            })
-        } else {
-            warn!("too many message waiting ack, downgrade to QoS level 0");
+    ack_timeout: Option<Duration>,
 
-            None
-        }
-    }
+    last_received: Option<Instant>,
 
-    fn on_subscribe_ack(&mut self, packet_id: PacketId, status: &[SubscribeReturnCode]) {
-        if let Some(Waiting::SubscribeAck { topic_filters, .. }) =
-            self.waiting_reply.remove(packet_id as usize)
-        {
-            debug!("subscribe {} acked", packet_id);
+    keep_alive: Option<Duration>,
 
-            let status = topic_filters
-                .iter()
-                .map(|&(topic, _)| topic)
-                .zip(status.iter().map(|code| *code))
-                .collect::<Vec<(&str, SubscribeReturnCode)>>();
+    ping_timeout: Option<Duration>,
 
-            self.handler.on_subscribed_topic(status.as_slice());
-        } else {
-            warn!("unexpected packet id {}", packet_id);
+    // the last clock tick after a packet was sent
+    last_sent: Option<Instant>,
+
+    // a packet was sent since the last clock tick
+    sent: bool,
+
+    ping_sent: Option<Instant>,
+
+    // a PINGREQ was queued since the last clock tick
+    ping_requested: bool,
+
+    // QoS 2 messages received from the Server which have not been released yet,
+    // holding the message until then in `DeliveryMode::OnRelease`.
+    received: HashMap<PacketId, Option<Message>>,
+
+    // the options of the last connection, used to reconnect
+    opts: Option<ConnectOptions>,
+
+    // the subscriptions granted by the server, restored when a session is not present
+    subscriptions: BTreeMap<String, QoS>,
+
+    // consecutive reconnect attempts since the last accepted connection
+    attempts: u32,
+
+    // a lost connection is being re-established following the reconnect policy
+    reconnecting: bool,
+
+    reconnect_at: Option<Instant>,
+
+    outgoing: VecDeque<Outgoing>,
+
+    events: VecDeque<Event>,
+
+    // messages published while disconnected, in order, with the bytes they hold
+    offline: VecDeque<(Option<PacketId>, Message)>,
+
+    offline_bytes: usize,
+
+    store: Option<Box<dyn SessionStore + Send>>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            state: State::Disconnected,
+            waiting_reply: HashMap::new(),
+            packet_ids: PacketIdAllocator::new(),
+            ack_timeout: None,
+            last_received: None,
+            keep_alive: None,
+            ping_timeout: None,
+            last_sent: None,
+            sent: false,
+            ping_sent: None,
+            ping_requested: false,
+            received: HashMap::new(),
+            opts: None,
+            subscriptions: BTreeMap::new(),
+            attempts: 0,
+            reconnecting: false,
+            reconnect_at: None,
+            outgoing: VecDeque::new(),
+            events: VecDeque::new(),
+            offline: VecDeque::new(),
+            offline_bytes: 0,
+            store: None,
         }
     }
+}
 
-    pub fn unsubscribe(&mut self, topic_filters: &'a [&'a str]) -> Option<Packet<'a>> {
-        if let Some(entry) = self.waiting_reply.vacant_entry() {
-            let packet_id = entry.index() as PacketId;
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-            entry.insert(Waiting::UnsubscribeAck {
-                packet_id: packet_id,
-                topic_filters: topic_filters,
-            });
+    /// Create a session resuming the in-flight state saved in `store`,
+    /// which keeps recording every change from then on.
+    pub fn with_store<S: SessionStore + Send + 'static>(store: S) -> Result<Session> {
+        let mut session = Session::new();
 
-            Some(Packet::Unsubscribe {
-                packet_id: packet_id,
-                topic_filters: From::from(topic_filters),
-            })
-        } else {
-            warn!("too many message waiting ack, downgrade to QoS level 0");
+        for (packet_id, mut packet) in store.outgoing()? {
+            // the server may have seen the message before the restart
+            if let Outgoing::Publish { ref mut dup, .. } = packet {
+                *dup = true;
+            }
 
-            None
+            session.waiting_reply.insert(packet_id, InFlight::new(packet));
         }
-    }
 
-    fn on_unsubscribe_ack(&mut self, packet_id: PacketId) {
-        if let Some(Waiting::UnsubscribeAck { topic_filters, .. }) =
-            self.waiting_reply.remove(packet_id as usize)
-        {
-            debug!("unsubscribe {} acked", packet_id);
+        session.received.extend(store.received()?);
 
-            self.handler.on_unsubscribed_topic(topic_filters)
-        } else {
-            warn!("unexpected packet id {}", packet_id)
+        if let Some(packet_id) = store.next_packet_id()?.filter(|&packet_id| packet_id > 0) {
+            session.packet_ids.next = packet_id;
         }
+
+        session.store = Some(Box::new(store));
+
+        Ok(session)
     }
-}
 
-pub struct Client<'a, T: Transport, H: 'a + Handler> {
-    transport: T,
-    session: Session<'a, H>,
-    client_id: ClientId,
-    keep_alive: Duration,
-}
+    pub fn state(&self) -> State {
+        self.state
+    }
 
-impl<'a, T: Transport, H: 'a + Handler> Client<'a, T, H> {
-    pub fn close(&mut self) -> Result<()> {
-        info!("client session closed");
+    /// Abandon requests which have not been acknowledged within `timeout`.
+    pub fn set_ack_timeout(&mut self, timeout: Option<Duration>) {
+        self.ack_timeout = timeout;
+    }
 
-        self.transport.close()
+    /// Returns the time the last packet was received from the server.
+    pub fn last_received(&self) -> Option<Instant> {
+        self.last_received
     }
-}
 
-impl<'a, T: Transport, H: 'a + Handler> transport::Handler<'a> for Client<'a, T, H> {
-    fn on_received_packet(&mut self, packet: &Packet<'a>) {
-        match *packet {
-            Packet::ConnectAck {
-                session_present,
-                return_code,
-            } => {
-                match return_code {
-                    ConnectReturnCode::ConnectionAccepted => {
-                        info!(
-                            "client session `{}` {}",
-                            self.client_id,
-                            if session_present {
-                                "resumed"
-                            } else {
-                                "created"
-                            }
-                        );
-
-                        for packet in self.session.delivery_retry() {
-                            if let Err(err) = self.transport.send_packet(&packet) {
-                                warn!("fail to send packet, {}", err)
-                            }
-                        }
-                    }
-                    _ => {
-                        info!(
-                            "client session `{}` refused, {}",
-                            self.client_id,
-                            return_code.reason()
-                        );
-
-                        if let Err(err) = self.close() {
-                            warn!("fail to close session, {}", err)
-                        }
-                    }
-                }
-            }
-            Packet::Publish {
-                dup,
-                retain,
-                qos,
-                topic,
-                packet_id,
-                payload,
-            } => {
-                self.session
-                    .on_publish(
-                        dup,
-                        retain,
-                        packet_id,
-                        Rc::new(Message {
-                            topic: topic,
-                            payload: payload,
-                            qos: qos,
-                        }),
-                    )
-                    .and_then(|packet| self.transport.send_packet(&packet).ok());
-            }
-            Packet::PublishAck { packet_id } => {
-                self.session.on_publish_ack(packet_id).and_then(|packet| {
-                    self.transport.send_packet(&packet).ok()
-                });
-            }
-            Packet::PublishReceived { packet_id } => {
-                self.session.on_publish_received(packet_id).and_then(
-                    |packet| {
-                        self.transport.send_packet(&packet).ok()
-                    },
-                );
-            }
-            Packet::PublishRelease { packet_id } => {
-                self.session.on_publish_release(packet_id).and_then(
-                    |packet| {
-                        self.transport.send_packet(&packet).ok()
-                    },
-                );
-            }
-            Packet::PublishComplete { packet_id } => {
-                self.session.on_publish_complete(packet_id).and_then(
-                    |packet| {
-                        self.transport.send_packet(&packet).ok()
-                    },
-                );
-            }
-            Packet::SubscribeAck {
-                packet_id,
-                ref status,
-            } => {
-                self.session.on_subscribe_ack(packet_id, status);
-            }
-            Packet::UnsubscribeAck { packet_id } => {
-                self.session.on_unsubscribe_ack(packet_id);
-            }
-            Packet::PingResponse => {
-                debug!("received ping response");
-            }
-            _ => {
-                warn!("unexpected packet {}", packet.packet_type());
+    /// Returns the number of requests waiting for an acknowledgment.
+    pub fn in_flight(&self) -> usize {
+        self.waiting_reply.len()
+    }
+
+    /// Forget every request waiting for an acknowledgment and every queued message,
+    /// each dropped QoS 1 or QoS 2 message is reported with `Event::PublishFailed`.
+    pub fn reset(&mut self) -> Result<()> {
+        let mut packet_ids = self.waiting_reply.keys().cloned().collect::<Vec<_>>();
+
+        packet_ids.sort();
+
+        for packet_id in packet_ids {
+            if self.forget(packet_id)?.is_some_and(|waiting| waiting.is_message()) {
+                self.events.push_back(Event::PublishFailed(packet_id));
             }
         }
-    }
-}
 
-pub struct Builder {
-    client_id: ClientId,
-    keep_alive: Duration,
-}
+        self.outgoing.clear();
+        self.offline.clear();
+        self.offline_bytes = 0;
 
-impl Builder {
-    pub fn client_id(mut self, client_id: ClientId) -> Self {
-        self.client_id = client_id;
-        self
+        Ok(())
     }
 
-    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
-        self.keep_alive = keep_alive;
-        self
+    /// Returns the number of messages published while disconnected and not sent yet.
+    pub fn queued(&self) -> usize {
+        self.offline.len()
     }
 
-    pub fn build<'a, T: Transport, H: 'a + Handler>(
-        self,
-        transport: T,
-        handler: &'a mut H,
-    ) -> Client<'a, T, H> {
-        Client {
-            transport: transport,
-            session: Session::new(handler),
-            client_id: self.client_id,
-            keep_alive: self.keep_alive,
-        }
+    /// Returns whether the in-flight window is full, a QoS 1 or QoS 2 message
+    /// published now would fail with `WouldBlock`.
+    pub fn is_window_full(&self) -> bool {
+        let max_in_flight = self.opts.as_ref().and_then(|opts| opts.max_in_flight);
+
+        max_in_flight.is_some_and(|max| {
+            self.waiting_reply
+                .values()
+                .filter(|waiting| waiting.is_message())
+                .count() >= max
+        })
     }
-}
 
-impl Default for Builder {
-    fn default() -> Self {
-        Builder {
-            client_id: ClientId::new(),
-            keep_alive: Duration::new(0, 0),
-        }
+    /// Returns the keep alive interval of the current connection.
+    pub fn keep_alive(&self) -> Option<Duration> {
+        self.keep_alive
+    }
+
+    /// Returns the next packet to send to the server.
+    ///
+    /// The IO layer should call `handle_tick` once the packets have been written,
+    /// so that the keep alive interval restarts from then.
+    pub fn poll_packet(&mut self) -> Option<Outgoing> {
+        let packet = self.outgoing.pop_front();
+
+        if let Some(Outgoing::Publish { packet_id: Some(packet_id), .. }) = packet {
+            if let Some(waiting) = self.waiting_reply.get_mut(&packet_id) {
+                waiting.written = true;
+            }
+        }
+
+        self.sent |= packet.is_some();
+
+        packet
+    }
+
+    /// Returns the next event to report to the application.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Returns the subscriptions granted by the server.
+    pub fn subscriptions(&self) -> &BTreeMap<String, QoS> {
+        &self.subscriptions
+    }
+
+    /// Returns whether a lost connection is being re-established.
+    pub fn is_reconnecting(&self) -> bool {
+        self.reconnecting
+    }
+
+    /// Start a new connection, discarding any packet not yet written for the previous one.
+    ///
+    /// A clean session drops the messages in flight on the previous connection,
+    /// each of them is reported with `Event::PublishFailed`.
+    /// It fails if the session store can't be cleared.
+    pub fn connect(&mut self, opts: ConnectOptions) -> Result<()> {
+        if opts.clean_session {
+            // messages in the offline queue are new, they don't belong to the old session
+            let mut dropped = self.waiting_reply
+                .iter()
+                .filter(|&(_, waiting)| !waiting.queued && waiting.is_message())
+                .map(|(&packet_id, _)| packet_id)
+                .collect::<Vec<_>>();
+
+            dropped.sort();
+            self.waiting_reply.retain(|_, waiting| waiting.queued);
+            self.events.extend(dropped.into_iter().map(Event::PublishFailed));
+            self.received.clear();
+            self.subscriptions.clear();
+
+            let queued = self.waiting_reply
+                .iter()
+                .map(|(&packet_id, waiting)| (packet_id, waiting.packet.clone()))
+                .collect::<Vec<_>>();
+
+            self.persist(|store| {
+                store.clear()?;
+
+                for (packet_id, packet) in queued {
+                    store.put_outgoing(packet_id, &packet)?;
+                }
+
+                Ok(())
+            })?;
+        }
+
+        self.keep_alive = match opts.keep_alive {
+            0 => None,
+            secs => Some(Duration::from_secs(u64::from(secs))),
+        };
+        self.ping_timeout = opts.ping_timeout.or(self.keep_alive);
+        self.attempts = 0;
+        self.reconnecting = false;
+        self.opts = Some(opts);
+
+        self.start();
+
+        Ok(())
+    }
+
+    /// Resend CONNECT with the options of the last connection, keeping the in-flight state.
+    pub fn reconnect(&mut self) -> Result<()> {
+        if self.opts.is_none() {
+            bail!(ErrorKind::InvalidState)
+        }
+
+        self.start();
+
+        Ok(())
+    }
+
+    fn start(&mut self) {
+        self.last_sent = None;
+        self.sent = false;
+        self.ping_sent = None;
+        self.ping_requested = false;
+        self.reconnect_at = None;
+
+        self.outgoing.clear();
+        self.outgoing.extend(self.opts.clone().map(|opts| Outgoing::Connect(Box::new(opts))));
+        self.state = State::Connecting;
+    }
+
+    /// Notify the session that the underlying connection has been lost at time `now`.
+    pub fn connection_lost(&mut self, now: Instant) {
+        self.lost(DisconnectReason::ConnectionLost, now)
+    }
+
+    fn lost(&mut self, reason: DisconnectReason, now: Instant) {
+        if self.state != State::Disconnected {
+            self.state = State::Disconnected;
+            self.outgoing.clear();
+            self.events.push_back(Event::Disconnected(reason));
+
+            let policy = self.opts.as_ref().and_then(|opts| opts.reconnect.as_ref());
+
+            if let Some(policy) = policy {
+                if policy.max_attempts.is_some_and(|max| self.attempts >= max) {
+                    warn!("give up reconnecting after {} attempts", self.attempts);
+
+                    self.reconnecting = false;
+                    self.events.push_back(Event::ReconnectFailed { attempts: self.attempts });
+                } else {
+                    self.reconnecting = true;
+                    self.reconnect_at = Some(now + policy.delay(self.attempts));
+                }
+            }
+        }
+    }
+
+    pub fn ping(&mut self) {
+        self.outgoing.push_back(Outgoing::PingRequest);
+        self.ping_requested = true;
+    }
+
+    pub fn disconnect(&mut self) {
+        self.outgoing.push_back(Outgoing::Disconnect);
+        self.reconnecting = false;
+        self.reconnect_at = None;
+        self.state = State::Disconnected;
+        self.events.push_back(Event::Disconnected(DisconnectReason::Requested));
+    }
+
+    /// Publish a message, returns the packet id of a QoS 1 or QoS 2 message.
+    ///
+    /// A message with an invalid topic name fails with `InvalidTopic`,
+    /// a QoS 1 or QoS 2 message fails with `WouldBlock` while the in-flight window is full.
+    /// Messages published while disconnected are held in the offline queue,
+    /// or fail with `QueueFull` if its overflow policy can't make room for them.
+    /// A QoS 1 or QoS 2 message fails if it can't be saved in the session store.
+    pub fn publish(&mut self, msg: Message) -> Result<Option<PacketId>> {
+        if !is_valid_topic_name(&msg.topic) {
+            bail!(ErrorKind::InvalidTopic)
+        }
+
+        if msg.qos != QoS::AtMostOnce && self.is_window_full() {
+            bail!(ErrorKind::WouldBlock)
+        }
+
+        if self.state != State::Connected {
+            return self.enqueue(msg);
+        }
+
+        if msg.qos == QoS::AtMostOnce {
+            self.outgoing.push_back(Outgoing::Publish {
+                dup: false,
+                retain: false,
+                packet_id: None,
+                msg,
+            });
+
+            return Ok(None);
+        }
+
+        let packet_id = self.next_packet_id()?;
+
+        self.wait_reply(
+            packet_id,
+            Outgoing::Publish {
+                dup: false,
+                retain: false,
+                packet_id: Some(packet_id),
+                msg,
+            },
+        )?;
+
+        Ok(Some(packet_id))
+    }
+
+    fn enqueue(&mut self, msg: Message) -> Result<Option<PacketId>> {
+        let limits = self.opts
+            .as_ref()
+            .map_or_else(OfflineQueue::default, |opts| opts.offline_queue);
+        let size = queued_size(&msg);
+
+        if limits.max_messages == 0 || size > limits.max_bytes {
+            bail!(ErrorKind::QueueFull)
+        }
+
+        while self.offline.len() >= limits.max_messages ||
+            self.offline_bytes + size > limits.max_bytes
+        {
+            let victim = match limits.overflow {
+                OverflowPolicy::RejectNew => None,
+                OverflowPolicy::DropOldest => Some(0),
+                OverflowPolicy::DropQoS0First => {
+                    self.offline
+                        .iter()
+                        .position(|(_, queued)| queued.qos == QoS::AtMostOnce)
+                }
+            };
+
+            match victim.and_then(|idx| self.offline.remove(idx)) {
+                Some((packet_id, dropped)) => {
+                    warn!("offline queue is full, drop message to `{}`", dropped.topic);
+
+                    self.offline_bytes -= queued_size(&dropped);
+
+                    if let Some(packet_id) = packet_id {
+                        self.forget(packet_id)?;
+                        self.events.push_back(Event::PublishFailed(packet_id));
+                    }
+                }
+                None => bail!(ErrorKind::QueueFull),
+            }
+        }
+
+        let packet_id = if msg.qos == QoS::AtMostOnce {
+            None
+        } else {
+            let packet_id = self.next_packet_id()?;
+
+            self.wait_reply(
+                packet_id,
+                Outgoing::Publish {
+                    dup: false,
+                    retain: false,
+                    packet_id: Some(packet_id),
+                    msg: msg.clone(),
+                },
+            )?;
+
+            if let Some(waiting) = self.waiting_reply.get_mut(&packet_id) {
+                waiting.queued = true;
+            }
+
+            Some(packet_id)
+        };
+
+        self.offline_bytes += size;
+        self.offline.push_back((packet_id, msg));
+
+        Ok(packet_id)
+    }
+
+    // Send the messages published while disconnected, in order.
+    fn flush_offline(&mut self) {
+        self.offline_bytes = 0;
+
+        while let Some((packet_id, msg)) = self.offline.pop_front() {
+            match packet_id {
+                Some(packet_id) => {
+                    if let Some(waiting) = self.waiting_reply.get_mut(&packet_id) {
+                        waiting.queued = false;
+
+                        self.outgoing.push_back(waiting.packet.clone());
+                    }
+                }
+                None => {
+                    self.outgoing.push_back(Outgoing::Publish {
+                        dup: false,
+                        retain: false,
+                        packet_id: None,
+                        msg,
+                    })
+                }
+            }
+        }
+    }
+
+    /// Subscribe to the topic filters, fails with `InvalidTopic` if any of them is invalid.
+    pub fn subscribe(&mut self, topic_filters: &[(&str, QoS)]) -> Result<PacketId> {
+        if !topic_filters.iter().all(|&(filter, _)| is_valid_topic_filter(filter)) {
+            bail!(ErrorKind::InvalidTopic)
+        }
+
+        let packet_id = self.next_packet_id()?;
+
+        self.wait_reply(
+            packet_id,
+            Outgoing::Subscribe {
+                packet_id,
+                topic_filters: topic_filters
+                    .iter()
+                    .map(|&(filter, qos)| (filter.to_owned(), qos))
+                    .collect(),
+            },
+        )?;
+
+        Ok(packet_id)
+    }
+
+    /// Unsubscribe from the topic filters, fails with `InvalidTopic` if any of them is invalid.
+    pub fn unsubscribe(&mut self, topic_filters: &[&str]) -> Result<PacketId> {
+        if !topic_filters.iter().all(|&filter| is_valid_topic_filter(filter)) {
+            bail!(ErrorKind::InvalidTopic)
+        }
+
+        let packet_id = self.next_packet_id()?;
+
+        self.wait_reply(
+            packet_id,
+            Outgoing::Unsubscribe {
+                packet_id,
+                topic_filters: topic_filters.iter().map(|&filter| filter.to_owned()).collect(),
+            },
+        )?;
+
+        Ok(packet_id)
+    }
+
+    fn next_packet_id(&mut self) -> Result<PacketId> {
+        let waiting_reply = &self.waiting_reply;
+        let packet_id = self.packet_ids
+            .allocate(|packet_id| waiting_reply.contains_key(&packet_id))?;
+        let next = self.packet_ids.next;
+
+        self.persist(|store| store.set_next_packet_id(next))?;
+
+        Ok(packet_id)
+    }
+
+    // Wait for the reply to `packet_id`, a message is only sent once it has been saved.
+    fn wait_reply(&mut self, packet_id: PacketId, packet: Outgoing) -> Result<()> {
+        let waiting = InFlight::new(packet);
+
+        if waiting.is_message() {
+            self.persist(|store| store.put_outgoing(packet_id, &waiting.packet))?;
+        }
+
+        if self.state == State::Connected {
+            self.outgoing.push_back(waiting.packet.clone());
+        }
+
+        self.waiting_reply.insert(packet_id, waiting);
+
+        Ok(())
+    }
+
+    // Stop waiting for the reply to `packet_id`, returns the request if there was one.
+    fn forget(&mut self, packet_id: PacketId) -> Result<Option<InFlight>> {
+        let waiting = self.waiting_reply.remove(&packet_id);
+
+        if waiting.as_ref().is_some_and(InFlight::is_message) {
+            self.persist(|store| store.remove_outgoing(packet_id))?;
+        }
+
+        Ok(waiting)
+    }
+
+    // Record a change in the session store.
+    fn persist<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn SessionStore) -> Result<()>,
+    {
+        match self.store {
+            Some(ref mut store) => {
+                f(&mut **store).map_err(|err| {
+                    warn!("fail to save session state, {}", err);
+
+                    err
+                })
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn delivery_retry(&mut self) {
+        let mut packet_ids = self.waiting_reply.keys().cloned().collect::<Vec<_>>();
+
+        packet_ids.sort();
+
+        for packet_id in packet_ids {
+            if let Some(waiting) = self.waiting_reply.get_mut(&packet_id) {
+                if waiting.queued {
+                    continue;
+                }
+
+                let packet = waiting.resend();
+
+                waiting.since = None;
+                waiting.sent = None;
+                waiting.retries = 0;
+
+                self.outgoing.push_back(packet);
+            }
+        }
+    }
+
+    /// Returns when `handle_tick` should be called next, if the session waits for anything.
+    pub fn next_tick(&self) -> Option<Instant> {
+        if self.state != State::Connected {
+            return self.reconnect_at;
+        }
+
+        let keep_alive = match (self.ping_sent, self.last_sent) {
+            (Some(ping_sent), _) => self.ping_timeout.map(|timeout| ping_sent + timeout),
+            (None, Some(last_sent)) => self.keep_alive.map(|keep_alive| last_sent + keep_alive),
+            (None, None) => None,
+        };
+
+        let ack = self.ack_timeout.and_then(|timeout| {
+            self.waiting_reply
+                .values()
+                .filter_map(|waiting| waiting.since)
+                .min()
+                .map(|since| since + timeout)
+        });
+
+        let retry = self.retry().and_then(|retry| {
+            self.waiting_reply
+                .values()
+                .filter(|waiting| waiting.is_message())
+                .filter_map(|waiting| waiting.sent)
+                .min()
+                .map(|sent| sent + retry.interval)
+        });
+
+        [keep_alive, ack, retry].iter().filter_map(|&tick| tick).min()
+    }
+
+    fn retry(&self) -> Option<RetryPolicy> {
+        self.opts.as_ref().and_then(|opts| opts.retry)
+    }
+
+    /// Advance the session clock, sending keep alive pings, resending unacknowledged messages
+    /// and abandoning requests whose acknowledgment timed out.
+    ///
+    /// It fails if an abandoned request can't be removed from the session store.
+    pub fn handle_tick(&mut self, now: Instant) -> Result<()> {
+        match self.reconnect_at {
+            Some(reconnect_at) if now >= reconnect_at => {
+                self.reconnect_at = None;
+                self.attempts += 1;
+
+                debug!("reconnect attempt {}", self.attempts);
+
+                self.events.push_back(Event::Reconnect { attempt: self.attempts });
+            }
+            _ => {}
+        }
+
+        if self.state != State::Connected {
+            return Ok(());
+        }
+
+        if self.sent || self.last_sent.is_none() {
+            self.sent = false;
+            self.last_sent = Some(now);
+        }
+
+        if let Some(ping_sent) = self.ping_sent {
+            if self.ping_timeout
+                .is_some_and(|timeout| now.duration_since(ping_sent) >= timeout)
+            {
+                warn!("ping response not received in time, connection is dead");
+
+                self.lost(DisconnectReason::KeepAliveTimeout, now);
+
+                return Ok(());
+            }
+        } else if let (Some(keep_alive), Some(last_sent)) = (self.keep_alive, self.last_sent) {
+            if now.duration_since(last_sent) >= keep_alive {
+                debug!("no packet sent in {:?}, ping the server", keep_alive);
+
+                self.ping();
+            }
+        }
+
+        if self.ping_requested {
+            self.ping_requested = false;
+            self.ping_sent.get_or_insert(now);
+        }
+
+        let retry = self.retry();
+        let mut expired = vec![];
+        let mut failed = vec![];
+        let mut resend = vec![];
+
+        for (&packet_id, waiting) in &mut self.waiting_reply {
+            let (since, sent) = match (waiting.since, waiting.sent) {
+                (Some(since), Some(sent)) => (since, sent),
+                _ => {
+                    waiting.since = Some(now);
+                    waiting.sent = Some(now);
+
+                    continue;
+                }
+            };
+
+            if self.ack_timeout
+                .is_some_and(|timeout| now.duration_since(since) >= timeout)
+            {
+                expired.push(packet_id);
+            } else if let Some(retry) = retry {
+                if waiting.is_message() && now.duration_since(sent) >= retry.interval {
+                    if retry.max_retries.is_some_and(|max| waiting.retries >= max) {
+                        failed.push(packet_id);
+                    } else {
+                        waiting.retries += 1;
+                        waiting.sent = Some(now);
+
+                        resend.push(packet_id);
+                    }
+                }
+            }
+        }
+
+        resend.sort();
+
+        for packet_id in resend {
+            if let Some(waiting) = self.waiting_reply.get_mut(&packet_id) {
+                debug!("resend packet {}, retry {}", packet_id, waiting.retries);
+
+                self.outgoing.push_back(waiting.resend());
+            }
+        }
+
+        expired.sort();
+
+        for packet_id in expired {
+            warn!("packet {} not acknowledged in time", packet_id);
+
+            self.forget(packet_id)?;
+            self.events.push_back(Event::Timeout(packet_id));
+        }
+
+        failed.sort();
+
+        for packet_id in failed {
+            warn!("message {} not acknowledged, give up resending it", packet_id);
+
+            self.forget(packet_id)?;
+            self.events.push_back(Event::PublishFailed(packet_id));
+        }
+
+        Ok(())
+    }
+
+    /// Process a packet received from the server at time `now`.
+    pub fn handle_packet(&mut self, packet: &Packet, now: Instant) -> Result<()> {
+        self.last_received = Some(now);
+
+        match *packet {
+            Packet::ConnectAck {
+                session_present,
+                return_code,
+            } => self.on_connect_ack(session_present, return_code),
+            Packet::Publish {
+                dup,
+                retain,
+                qos,
+                topic,
+                packet_id,
+                payload,
+            } => self.on_publish(dup, retain, packet_id, Message::new(topic, payload, qos)),
+            Packet::PublishAck { packet_id } => self.on_publish_ack(packet_id),
+            Packet::PublishReceived { packet_id } => self.on_publish_received(packet_id),
+            Packet::PublishRelease { packet_id } => self.on_publish_release(packet_id),
+            Packet::PublishComplete { packet_id } => self.on_publish_complete(packet_id),
+            Packet::SubscribeAck {
+                packet_id,
+                ref status,
+            } => self.on_subscribe_ack(packet_id, status),
+            Packet::UnsubscribeAck { packet_id } => self.on_unsubscribe_ack(packet_id),
+            Packet::PingResponse => {
+                debug!("received ping response");
+
+                if let Some(ping_sent) = self.ping_sent.take() {
+                    self.events.push_back(Event::Pong {
+                        latency: now.duration_since(ping_sent),
+                    });
+                }
+
+                Ok(())
+            }
+            _ => {
+                warn!("unexpected packet {}", packet.packet_type());
+
+                bail!(ErrorKind::InvalidPacket)
+            }
+        }
+    }
+
+    fn on_connect_ack(
+        &mut self,
+        session_present: bool,
+        return_code: ConnectReturnCode,
+    ) -> Result<()> {
+        if self.state != State::Connecting {
+            warn!("unexpected connect ack in {:?} state", self.state);
+
+            bail!(ErrorKind::InvalidState)
+        }
+
+        if return_code == ConnectReturnCode::ConnectionAccepted {
+            debug!(
+                "session {}",
+                if session_present { "resumed" } else { "created" }
+            );
+
+            self.state = State::Connected;
+            self.attempts = 0;
+            self.reconnecting = false;
+            self.events.push_back(Event::Connected { session_present });
+            self.delivery_retry();
+
+            if !session_present && !self.subscriptions.is_empty() {
+                self.resubscribe()?;
+            }
+
+            self.flush_offline();
+        } else {
+            debug!("session refused, {}", return_code.reason());
+
+            self.state = State::Disconnected;
+            self.reconnecting = false;
+            self.events.push_back(Event::ConnectRefused(return_code));
+        }
+
+        Ok(())
+    }
+
+    // Restore the subscriptions of a session the server doesn't remember.
+    fn resubscribe(&mut self) -> Result<()> {
+        let topic_filters = self.subscriptions
+            .iter()
+            .map(|(filter, &qos)| (filter.clone(), qos))
+            .collect();
+
+        match self.next_packet_id() {
+            Ok(packet_id) => {
+                debug!("resubscribe {} topic filters", self.subscriptions.len());
+
+                self.wait_reply(
+                    packet_id,
+                    Outgoing::Subscribe {
+                        packet_id,
+                        topic_filters,
+                    },
+                )
+            }
+            Err(err) => {
+                warn!("fail to resubscribe, {}", err);
+
+                Err(err)
+            }
+        }
+    }
+
+    fn on_publish(
+        &mut self,
+        _dup: bool,
+        _retain: bool,
+        packet_id: Option<PacketId>,
+        msg: Message,
+    ) -> Result<()> {
+        match (msg.qos, packet_id) {
+            (QoS::AtLeastOnce, Some(packet_id)) => {
+                self.outgoing.push_back(Outgoing::PublishAck(packet_id))
+            }
+            (QoS::ExactlyOnce, Some(packet_id)) => {
+                if self.received.contains_key(&packet_id) {
+                    debug!("drop duplicated message {}", packet_id);
+
+                    self.outgoing.push_back(Outgoing::PublishReceived(packet_id));
+
+                    return Ok(());
+                }
+
+                // PUBREC is only sent once the message has been saved
+                if self.delivery() == DeliveryMode::OnRelease {
+                    self.persist(|store| store.put_received(packet_id, Some(&msg)))?;
+                    self.outgoing.push_back(Outgoing::PublishReceived(packet_id));
+                    self.received.insert(packet_id, Some(msg));
+
+                    return Ok(());
+                }
+
+                self.persist(|store| store.put_received(packet_id, None))?;
+                self.outgoing.push_back(Outgoing::PublishReceived(packet_id));
+                self.received.insert(packet_id, None);
+            }
+            _ => {}
+        }
+
+        self.events.push_back(Event::Message(msg));
+
+        Ok(())
+    }
+
+    fn delivery(&self) -> DeliveryMode {
+        self.opts
+            .as_ref()
+            .map_or_else(DeliveryMode::default, |opts| opts.delivery)
+    }
+
+    fn on_publish_ack(&mut self, packet_id: PacketId) -> Result<()> {
+        if self.forget(packet_id)?.is_some() {
+            debug!("message {} acknowledged", packet_id);
+
+            self.events.push_back(Event::Published(packet_id));
+        } else {
+            warn!("unexpected packet id {}", packet_id)
+        }
+
+        Ok(())
+    }
+
+    fn on_publish_received(&mut self, packet_id: PacketId) -> Result<()> {
+        if self.waiting_reply.contains_key(&packet_id) {
+            debug!("message {} received at server side", packet_id);
+
+            let waiting = InFlight::new(Outgoing::PublishRelease(packet_id));
+
+            self.persist(|store| store.put_outgoing(packet_id, &waiting.packet))?;
+            self.waiting_reply.insert(packet_id, waiting);
+            self.outgoing.push_back(Outgoing::PublishRelease(packet_id));
+        } else {
+            warn!("unexpected packet id {}", packet_id);
+        }
+
+        Ok(())
+    }
+
+    fn on_publish_release(&mut self, packet_id: PacketId) -> Result<()> {
+        if self.received.contains_key(&packet_id) {
+            self.persist(|store| store.remove_received(packet_id))?;
+
+            if let Some(Some(msg)) = self.received.remove(&packet_id) {
+                self.events.push_back(Event::Message(msg));
+            }
+        } else {
+            debug!("message {} already released", packet_id);
+        }
+
+        self.outgoing.push_back(Outgoing::PublishComplete(packet_id));
+
+        Ok(())
+    }
+
+    fn on_publish_complete(&mut self, packet_id: PacketId) -> Result<()> {
+        if self.forget(packet_id)?.is_some() {
+            debug!("message {} completed", packet_id);
+
+            self.events.push_back(Event::Published(packet_id));
+        } else {
+            warn!("unexpected packet id {}", packet_id)
+        }
+
+        Ok(())
+    }
+
+    fn on_subscribe_ack(
+        &mut self,
+        packet_id: PacketId,
+        status: &[SubscribeReturnCode],
+    ) -> Result<()> {
+        if let Some(InFlight { packet: Outgoing::Subscribe { topic_filters, .. }, .. }) =
+            self.forget(packet_id)?
+        {
+            debug!("subscribe {} acked", packet_id);
+
+            let status: Vec<_> = topic_filters
+                .into_iter()
+                .map(|(topic, _)| topic)
+                .zip(status.iter().cloned())
+                .collect();
+
+            for &(ref filter, code) in &status {
+                match code {
+                    SubscribeReturnCode::Success(qos) => {
+                        self.subscriptions.insert(filter.clone(), qos);
+                    }
+                    SubscribeReturnCode::Failure => {
+                        self.subscriptions.remove(filter);
+                    }
+                }
+            }
+
+            self.events.push_back(Event::Subscribed { packet_id, status });
+        } else {
+            warn!("unexpected packet id {}", packet_id);
+        }
+
+        Ok(())
+    }
+
+    fn on_unsubscribe_ack(&mut self, packet_id: PacketId) -> Result<()> {
+        if let Some(InFlight { packet: Outgoing::Unsubscribe { topic_filters, .. }, .. }) =
+            self.forget(packet_id)?
+        {
+            debug!("unsubscribe {} acked", packet_id);
+
+            for filter in &topic_filters {
+                self.subscriptions.remove(filter);
+            }
+
+            self.events.push_back(Event::Unsubscribed {
+                packet_id,
+                topic_filters,
+            });
+        } else {
+            warn!("unexpected packet id {}", packet_id)
+        }
+
+        Ok(())
+    }
+}
+
+// The bytes a message holds in the offline queue.
+fn queued_size(msg: &Message) -> usize {
+    msg.topic.len() + msg.payload.len()
+}
+
+// A callback registered with `Client::subscribe_with`.
+type MessageCallback = Box<dyn FnMut(&Message) + Send>;
+
+/// Drives a `Session` over a `Transport`, dispatching its events to a `Handler`.
+///
+/// Messages are delivered to every callback registered with `subscribe_with`
+/// against a matching topic filter, or to `Handler::on_received_message` when none match.
+pub struct Client<T: Transport, H: Handler> {
+    transport: T,
+    session: Session,
+    handler: H,
+    opts: ConnectOptions,
+    deliveries: HashMap<PacketId, DeliveryToken>,
+    callbacks: TopicTree<Vec<MessageCallback>>,
+    // callbacks waiting for the SUBACK of their subscription
+    subscribing: HashMap<PacketId, (Topic, MessageCallback)>,
+}
+
+impl<T: Transport, H: Handler> Client<T, H> {
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// The options sent in the CONNECT packet.
+    pub fn options(&self) -> &ConnectOptions {
+        &self.opts
+    }
+
+    /// Send CONNECT with the options of the builder.
+    ///
+    /// It should be called once the transport connects. Once connected,
+    /// `tick` should be called at `session().next_tick()` to keep the connection alive.
+    pub fn connect(&mut self) -> Result<()> {
+        if self.opts.clean_session {
+            // the subscriptions in flight are dropped with the old session
+            self.subscribing.clear();
+        }
+
+        self.session.connect(self.opts.clone())?;
+
+        self.flush()
+    }
+
+    /// Publish a message, returning a token to track its delivery.
+    ///
+    /// The token of a QoS 0 message is published as soon as the session accepts it,
+    /// the others complete on acknowledgment, reported with `Handler::on_published`,
+    /// or on failure, reported with `Handler::on_publish_failed`.
+    pub fn publish(&mut self, msg: Message) -> Result<DeliveryToken> {
+        let packet_id = self.session.publish(msg)?;
+        let token = DeliveryToken::new(packet_id);
+
+        match packet_id {
+            Some(packet_id) => {
+                self.deliveries.insert(packet_id, token.clone());
+            }
+            None => token.complete(DeliveryState::Published),
+        }
+
+        self.flush()?;
+
+        Ok(token)
+    }
+
+    pub fn subscribe(&mut self, topic_filters: &[(&str, QoS)]) -> Result<PacketId> {
+        let packet_id = self.session.subscribe(topic_filters)?;
+
+        self.flush()?;
+
+        Ok(packet_id)
+    }
+
+    /// Subscribe to the topic filter, delivering the matching messages to `callback`.
+    ///
+    /// Several callbacks may be registered against the same or overlapping filters,
+    /// a message is delivered to all of them.
+    /// The callback is only installed once the server granted the subscription.
+    pub fn subscribe_with<F>(
+        &mut self,
+        topic_filter: &str,
+        qos: QoS,
+        callback: F,
+    ) -> Result<PacketId>
+    where
+        F: FnMut(&Message) + Send + 'static,
+    {
+        let filter = TopicFilter::new(topic_filter)?.into_topic();
+        let packet_id = self.session.subscribe(&[(topic_filter, qos)])?;
+
+        self.subscribing.insert(packet_id, (filter, Box::new(callback)));
+
+        self.flush()?;
+
+        Ok(packet_id)
+    }
+
+    /// Unsubscribe from the topic filters, their callbacks are removed
+    /// once the server acknowledged it.
+    pub fn unsubscribe(&mut self, topic_filters: &[&str]) -> Result<PacketId> {
+        let packet_id = self.session.unsubscribe(topic_filters)?;
+
+        self.flush()?;
+
+        Ok(packet_id)
+    }
+
+    pub fn ping(&mut self) -> Result<()> {
+        self.session.ping();
+
+        self.flush()
+    }
+
+    pub fn tick(&mut self) -> Result<()> {
+        self.session.handle_tick(Instant::now())?;
+
+        self.flush()
+    }
+
+    /// Notify the client that the transport lost its connection,
+    /// a new one is attempted by `tick` following the reconnect policy.
+    pub fn connection_lost(&mut self) {
+        self.session.connection_lost(Instant::now());
+
+        while let Some(event) = self.session.poll_event() {
+            self.dispatch(event);
+        }
+    }
+
+    pub fn disconnect(&mut self) -> Result<()> {
+        self.session.disconnect();
+
+        self.flush()?;
+        self.close()
+    }
+
+    pub fn close(&mut self) -> Result<()> {
+        info!("client session closed");
+
+        self.transport.close()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        loop {
+            let mut sent = false;
+
+            while let Some(packet) = self.session.poll_packet() {
+                self.transport.send_packet(&packet.packet())?;
+
+                sent = true;
+            }
+
+            if !sent {
+                break;
+            }
+
+            self.session.handle_tick(Instant::now())?;
+        }
+
+        let mut dispatched = false;
+
+        while let Some(event) = self.session.poll_event() {
+            self.dispatch(event);
+
+            dispatched = true;
+        }
+
+        // a reconnect attempt may have queued a CONNECT packet
+        if dispatched {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn dispatch(&mut self, event: Event) {
+        match event {
+            Event::Connected { session_present } => {
+                info!(
+                    "client session `{}` {}",
+                    self.opts.client_id,
+                    if session_present {
+                        "resumed"
+                    } else {
+                        "created"
+                    }
+                );
+
+                self.handler.on_connected(session_present)
+            }
+            Event::ConnectRefused(return_code) => {
+                info!(
+                    "client session `{}` refused, {}",
+                    self.opts.client_id,
+                    return_code.reason()
+                );
+
+                if let Err(err) = self.close() {
+                    warn!("fail to close session, {}", err)
+                }
+
+                self.handler.on_connect_refused(return_code)
+            }
+            Event::Message(msg) => {
+                if !self.deliver(&msg) {
+                    self.handler.on_received_message(&msg)
+                }
+            }
+            Event::Subscribed { packet_id, status } => {
+                if let Some((filter, callback)) = self.subscribing.remove(&packet_id) {
+                    match status.first() {
+                        Some(&(_, SubscribeReturnCode::Success(_))) => {
+                            self.callbacks.add(&filter).push(callback)
+                        }
+                        _ => warn!("subscription to `{}` refused, drop its callback", filter),
+                    }
+                }
+
+                let status = status
+                    .iter()
+                    .map(|&(ref topic, code)| (topic.as_str(), code))
+                    .collect::<Vec<_>>();
+
+                self.handler.on_subscribed_topic(&status)
+            }
+            Event::Unsubscribed { topic_filters, .. } => {
+                for topic_filter in &topic_filters {
+                    if let Ok(filter) = topic_filter.parse::<Topic>() {
+                        self.callbacks.remove(&filter);
+                    }
+                }
+
+                let topic_filters = topic_filters
+                    .iter()
+                    .map(|filter| filter.as_str())
+                    .collect::<Vec<_>>();
+
+                self.handler.on_unsubscribed_topic(&topic_filters)
+            }
+            Event::Published(packet_id) => {
+                debug!("message {} published", packet_id);
+
+                if let Some(token) = self.deliveries.remove(&packet_id) {
+                    token.complete(DeliveryState::Published);
+
+                    self.handler.on_published(&token)
+                }
+            }
+            Event::Timeout(packet_id) => {
+                warn!("packet {} timed out", packet_id);
+
+                self.subscribing.remove(&packet_id);
+                self.delivery_failed(packet_id, DeliveryFailure::Timeout)
+            }
+            Event::PublishFailed(packet_id) => {
+                warn!("message {} failed", packet_id);
+
+                self.delivery_failed(packet_id, DeliveryFailure::Abandoned)
+            }
+            Event::Reconnect { attempt } => {
+                info!(
+                    "client session `{}` reconnecting, attempt {}",
+                    self.opts.client_id,
+                    attempt
+                );
+
+                let transport = &mut self.transport;
+
+                if let Err(err) = self.session.reconnect().and_then(|_| transport.reconnect()) {
+                    warn!("fail to reconnect, {}", err);
+
+                    self.session.connection_lost(Instant::now());
+                }
+            }
+            Event::ReconnectFailed { attempts } => {
+                info!(
+                    "client session `{}` gave up reconnecting after {} attempts",
+                    self.opts.client_id,
+                    attempts
+                );
+            }
+            Event::Disconnected(reason) => {
+                info!(
+                    "client session `{}` disconnected, {:?}",
+                    self.opts.client_id,
+                    reason
+                );
+
+                if reason == DisconnectReason::KeepAliveTimeout {
+                    if let Err(err) = self.close() {
+                        warn!("fail to close session, {}", err)
+                    }
+                }
+
+                self.handler.on_disconnected(reason)
+            }
+            Event::Pong { latency } => {
+                debug!(
+                    "client session `{}` ping latency {:?}",
+                    self.opts.client_id,
+                    latency
+                );
+
+                self.handler.on_pong(latency)
+            }
+        }
+    }
+
+    // Call the callbacks of all the filters matching the message, returns false if none match.
+    fn deliver(&mut self, msg: &Message) -> bool {
+        if self.callbacks.is_empty() {
+            return false;
+        }
+
+        let filters = match msg.topic.parse::<Topic>() {
+            Ok(topic) => {
+                self.callbacks
+                    .matches(&topic)
+                    .into_iter()
+                    .map(|(filter, _)| filter.clone())
+                    .collect::<Vec<_>>()
+            }
+            Err(_) => return false,
+        };
+
+        for filter in &filters {
+            if let Some(callbacks) = self.callbacks.get_mut(filter) {
+                for callback in callbacks {
+                    callback(msg)
+                }
+            }
+        }
+
+        !filters.is_empty()
+    }
+
+    fn delivery_failed(&mut self, packet_id: PacketId, reason: DeliveryFailure) {
+        if let Some(token) = self.deliveries.remove(&packet_id) {
+            token.complete(DeliveryState::Failed(reason));
+
+            self.handler.on_publish_failed(&token, reason)
+        }
+    }
+}
+
+impl<'a, T: Transport, H: Handler> transport::Handler<'a> for Client<T, H> {
+    fn on_received_packet(&mut self, packet: &Packet<'a>) {
+        if let Err(err) = self.session.handle_packet(packet, Instant::now()) {
+            warn!("fail to handle packet, {}", err);
+        }
+
+        if let Err(err) = self.flush() {
+            warn!("fail to send packet, {}", err);
+
+            self.connection_lost();
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Builder {
+    opts: ConnectOptions,
+    endpoint: Option<Endpoint>,
+    session: Option<Session>,
+}
+
+impl Builder {
+    pub fn client_id(mut self, client_id: ClientId) -> Self {
+        self.opts.client_id = client_id;
+        self
+    }
+
+    /// The protocol level sent in CONNECT, MQTT 3.1.1 by default.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.opts.protocol = protocol;
+        self
+    }
+
+    /// Resume the session kept by the server instead of starting a clean one.
+    pub fn clean_session(mut self, clean_session: bool) -> Self {
+        self.opts.clean_session = clean_session;
+        self
+    }
+
+    /// The keep alive interval, rounded down to seconds, `0` disables it.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.opts.keep_alive = cmp::min(keep_alive.as_secs(), u64::from(u16::MAX)) as u16;
+        self
+    }
+
+    /// How long to wait for a PINGRESP before the connection is considered dead.
+    pub fn ping_timeout(mut self, ping_timeout: Duration) -> Self {
+        self.opts.ping_timeout = Some(ping_timeout);
+        self
+    }
+
+    pub fn username<S: Into<String>>(mut self, username: S) -> Self {
+        self.opts.username = Some(username.into());
+        self
+    }
+
+    pub fn password<P: Into<Vec<u8>>>(mut self, password: P) -> Self {
+        self.opts.password = Some(password.into());
+        self
+    }
+
+    /// The message the server publishes when the connection is lost without DISCONNECT.
+    pub fn last_will(mut self, msg: Message, retain: bool) -> Self {
+        self.opts.last_will = Some(msg);
+        self.opts.will_retain = retain;
+        self
+    }
+
+    /// Reconnect with `policy` when the connection is lost,
+    /// each attempt fails unless the transport implements `Transport::reconnect`.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.opts.reconnect = Some(policy);
+        self
+    }
+
+    /// Deliver QoS 2 messages to the handler on PUBLISH or only once released by PUBREL.
+    pub fn delivery(mut self, delivery: DeliveryMode) -> Self {
+        self.opts.delivery = delivery;
+        self
+    }
+
+    /// Resend unacknowledged messages with `policy` while the connection is alive.
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.opts.retry = Some(policy);
+        self
+    }
+
+    /// Hold the messages published while disconnected in a queue bounded by `queue`.
+    pub fn offline_queue(mut self, queue: OfflineQueue) -> Self {
+        self.opts.offline_queue = queue;
+        self
+    }
+
+    /// Limit the QoS 1 and QoS 2 messages waiting for acknowledgment,
+    /// `publish` fails with `WouldBlock` once `max` are in flight.
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        self.opts.max_in_flight = Some(max);
+        self
+    }
+
+    /// Resume a session, usually restored with `Session::with_store`.
+    pub fn session(mut self, session: Session) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// The options the client will send in CONNECT.
+    pub fn options(&self) -> &ConnectOptions {
+        &self.opts
+    }
+
+    /// Build a client over a transport already connected to the server,
+    /// the endpoint is only used by `blocking::Client::with_builder`
+    /// and `AsyncClient::with_builder`, which open the connection themselves.
+    pub fn build<T: Transport, H: Handler>(self, transport: T, handler: H) -> Client<T, H> {
+        Client {
+            transport: transport,
+            session: self.session.unwrap_or_default(),
+            handler,
+            opts: self.opts,
+            deliveries: HashMap::new(),
+            callbacks: TopicTree::new(),
+            subscribing: HashMap::new(),
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    extern crate env_logger;
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::thread;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use decode::read_packet;
+    use encode::WritePacketExt;
+    use super::*;
+
+    // A minimal broker which acknowledges everything and echoes published messages back.
+    pub fn echo_broker(pings: Arc<AtomicUsize>) -> (String, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = vec![];
+            let mut data = [0; 1024];
+
+            loop {
+                let n = stream.read(&mut data).unwrap();
+
+                if n == 0 {
+                    return;
+                }
+
+                buf.extend_from_slice(&data[..n]);
+
+                let mut out = vec![];
+                let mut offset = 0;
+
+                while let Ok((remaining, packet)) = read_packet(&buf[offset..]) {
+                    offset = buf.len() - remaining.len();
+
+                    match packet {
+                        Packet::Connect { .. } => {
+                            out.write_packet(&Packet::ConnectAck {
+                                session_present: false,
+                                return_code: ConnectReturnCode::ConnectionAccepted,
+                            }).unwrap();
+                        }
+                        Packet::Subscribe {
+                            packet_id,
+                            topic_filters,
+                        } => {
+                            out.write_packet(&Packet::SubscribeAck {
+                                packet_id,
+                                status: topic_filters
+                                    .iter()
+                                    .map(|&(_, qos)| SubscribeReturnCode::Success(qos))
+                                    .collect(),
+                            }).unwrap();
+                        }
+                        Packet::Publish {
+                            qos,
+                            topic,
+                            packet_id,
+                            payload,
+                            ..
+                        } => {
+                            match (qos, packet_id) {
+                                (QoS::AtLeastOnce, Some(packet_id)) => {
+                                    out.write_packet(&Packet::PublishAck { packet_id })
+                                }
+                                (QoS::ExactlyOnce, Some(packet_id)) => {
+                                    out.write_packet(&Packet::PublishReceived { packet_id })
+                                }
+                                _ => Ok(0),
+                            }.unwrap();
+
+                            out.write_packet(&Packet::Publish {
+                                dup: false,
+                                retain: false,
+                                qos: QoS::AtMostOnce,
+                                topic,
+                                packet_id: None,
+                                payload,
+                            }).unwrap();
+                        }
+                        Packet::PublishRelease { packet_id } => {
+                            out.write_packet(&Packet::PublishComplete { packet_id })
+                                .unwrap();
+                        }
+                        Packet::PingRequest => {
+                            pings.fetch_add(1, Ordering::SeqCst);
+
+                            out.write_packet(&Packet::PingResponse).unwrap();
+                        }
+                        Packet::Disconnect => return,
+                        _ => {}
+                    }
+                }
+
+                buf.drain(..offset);
+                stream.write_all(&out).unwrap();
+            }
+        });
+
+        (addr, handle)
+    }
+
+    fn connected() -> Session {
+        let mut session = Session::new();
+
+        session.connect(ConnectOptions::default()).unwrap();
+        session.poll_packet().unwrap();
+        session
+            .handle_packet(
+                &Packet::ConnectAck {
+                    session_present: false,
+                    return_code: ConnectReturnCode::ConnectionAccepted,
+                },
+                Instant::now(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            session.poll_event(),
+            Some(Event::Connected { session_present: false })
+        );
+
+        session
+    }
+
+    fn drain(session: &mut Session) -> Vec<Outgoing> {
+        let mut packets = vec![];
+
+        while let Some(packet) = session.poll_packet() {
+            packets.push(packet);
+        }
+
+        packets
+    }
+
+    #[test]
+    fn test_packet_id_allocator() {
+        let mut ids = PacketIdAllocator::new();
+
+        assert_eq!(ids.allocate(|_| false).unwrap(), 1);
+        assert_eq!(ids.allocate(|_| false).unwrap(), 2);
+        assert_eq!(ids.allocate(|id| id == 3 || id == 4).unwrap(), 5);
+
+        let mut ids = PacketIdAllocator { next: PacketId::MAX };
+
+        assert_eq!(ids.allocate(|_| false).unwrap(), 65535);
+        assert_eq!(ids.allocate(|_| false).unwrap(), 1);
+        assert_eq!(ids.allocate(|id| id != 42).unwrap(), 42);
+
+        match ids.allocate(|_| true) {
+            Err(Error(ErrorKind::PacketIdExhausted, _)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_session_connect() {
+        let mut session = Session::new();
+        let opts = ConnectOptions {
+            username: Some("user".to_owned()),
+            password: Some(b"pass".to_vec()),
+            ..Default::default()
+        };
+
+        assert_eq!(session.state(), State::Disconnected);
+
+        session.connect(opts.clone()).unwrap();
+
+        assert_eq!(session.state(), State::Connecting);
+
+        match session.poll_packet().unwrap().packet() {
+            Packet::Connect {
+                client_id,
+                username,
+                password,
+                ..
+            } => {
+                assert_eq!(client_id, &*opts.client_id);
+                assert_eq!(username, Some("user"));
+                assert_eq!(password, Some(&b"pass"[..]));
+            }
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+
+        session
+            .handle_packet(
+                &Packet::ConnectAck {
+                    session_present: false,
+                    return_code: ConnectReturnCode::NotAuthorized,
+                },
+                Instant::now(),
+            )
+            .unwrap();
+
+        assert_eq!(session.state(), State::Disconnected);
+        assert_eq!(
+            session.poll_event(),
+            Some(Event::ConnectRefused(ConnectReturnCode::NotAuthorized))
+        );
+        assert!(
+            session
+                .handle_packet(&Packet::PingRequest, Instant::now())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_session_publish() {
+        let mut session = connected();
+
+        // QoS 1
+        assert_eq!(
+            session
+                .publish(Message::new("topic", &b"data"[..], QoS::AtLeastOnce))
+                .unwrap(),
+            Some(1)
+        );
+        assert_eq!(drain(&mut session).len(), 1);
+
+        session
+            .handle_packet(&Packet::PublishAck { packet_id: 1 }, Instant::now())
+            .unwrap();
+
+        assert_eq!(session.poll_event(), Some(Event::Published(1)));
+        assert_eq!(session.in_flight(), 0);
+
+        // QoS 2
+        assert_eq!(
+            session
+                .publish(Message::new("topic", &b"data"[..], QoS::ExactlyOnce))
+                .unwrap(),
+            Some(2)
+        );
+        assert_eq!(drain(&mut session).len(), 1);
+
+        session
+            .handle_packet(&Packet::PublishReceived { packet_id: 2 }, Instant::now())
+            .unwrap();
+
+        assert_eq!(drain(&mut session), vec![Outgoing::PublishRelease(2)]);
+        assert_eq!(session.poll_event(), None);
+
+        session
+            .handle_packet(&Packet::PublishComplete { packet_id: 2 }, Instant::now())
+            .unwrap();
+
+        assert_eq!(session.poll_event(), Some(Event::Published(2)));
+
+        // QoS 0
+        assert_eq!(
+            session
+                .publish(Message::new("topic", &b"data"[..], QoS::AtMostOnce))
+                .unwrap(),
+            None
+        );
+        assert_eq!(session.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_session_receive() {
+        let mut session = connected();
+
+        session
+            .handle_packet(
+                &Packet::Publish {
+                    dup: false,
+                    retain: false,
+                    qos: QoS::ExactlyOnce,
+                    topic: "topic",
+                    packet_id: Some(7),
+                    payload: b"data",
+                },
+                Instant::now(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            session.poll_event(),
+            Some(Event::Message(
+                Message::new("topic", &b"data"[..], QoS::ExactlyOnce),
+            ))
+        );
+        assert_eq!(drain(&mut session), vec![Outgoing::PublishReceived(7)]);
+
+        session
+            .handle_packet(&Packet::PublishRelease { packet_id: 7 }, Instant::now())
+            .unwrap();
+
+        assert_eq!(drain(&mut session), vec![Outgoing::PublishComplete(7)]);
+    }
+
+    #[test]
+    fn test_session_receive_exactly_once() {
+        let publish = |session: &mut Session, dup, payload: &'static [u8]| {
+            session
+                .handle_packet(
+                    &Packet::Publish {
+                        dup,
+                        retain: false,
+                        qos: QoS::ExactlyOnce,
+                        topic: "topic",
+                        packet_id: Some(7),
+                        payload,
+                    },
+                    Instant::now(),
+                )
+                .unwrap()
+        };
+        let release = |session: &mut Session| {
+            session
+                .handle_packet(&Packet::PublishRelease { packet_id: 7 }, Instant::now())
+                .unwrap()
+        };
+        let msg = |payload: &'static [u8]| {
+            Some(Event::Message(Message::new("topic", payload, QoS::ExactlyOnce)))
+        };
+
+        // a resent PUBLISH is acknowledged again but delivered once
+        let mut session = connected();
+
+        publish(&mut session, false, b"first");
+        publish(&mut session, true, b"first");
+
+        assert_eq!(session.poll_event(), msg(b"first"));
+        assert_eq!(session.poll_event(), None);
+        assert_eq!(
+            drain(&mut session),
+            vec![Outgoing::PublishReceived(7), Outgoing::PublishReceived(7)]
+        );
+
+        release(&mut session);
+        release(&mut session);
+
+        assert_eq!(session.poll_event(), None);
+        assert_eq!(
+            drain(&mut session),
+            vec![Outgoing::PublishComplete(7), Outgoing::PublishComplete(7)]
+        );
+
+        // the packet id may be reused once released
+        publish(&mut session, false, b"second");
+
+        assert_eq!(session.poll_event(), msg(b"second"));
+
+        // or the delivery deferred until PUBREL
+        let mut session = Session::new();
+
+        session.connect(ConnectOptions {
+            delivery: DeliveryMode::OnRelease,
+            ..Default::default()
+        }).unwrap();
+        session
+            .handle_packet(
+                &Packet::ConnectAck {
+                    session_present: false,
+                    return_code: ConnectReturnCode::ConnectionAccepted,
+                },
+                Instant::now(),
+            )
+            .unwrap();
+        session.poll_event();
+
+        publish(&mut session, false, b"first");
+        publish(&mut session, true, b"first");
+
+        assert_eq!(session.poll_event(), None);
+
+        release(&mut session);
+
+        assert_eq!(session.poll_event(), msg(b"first"));
+        assert_eq!(session.poll_event(), None);
+    }
+
+    #[test]
+    fn test_session_invalid_topic() {
+        fn invalid<T>(res: Result<T>) -> bool {
+            matches!(res, Err(Error(ErrorKind::InvalidTopic, _)))
+        }
+
+        let mut session = connected();
+
+        assert!(invalid(session.publish(Message::new("a/+", &b"data"[..], QoS::AtLeastOnce))));
+        assert!(invalid(session.publish(Message::new("", &b"data"[..], QoS::AtMostOnce))));
+        assert!(invalid(
+            session.subscribe(&[("a", QoS::AtMostOnce), ("a/#/b", QoS::AtMostOnce)])
+        ));
+        assert!(invalid(session.unsubscribe(&[""])));
+
+        // nothing is queued and no packet id is used
+        assert_eq!(session.poll_packet(), None);
+        assert_eq!(session.in_flight(), 0);
+        assert_eq!(
+            session.publish(Message::new("a/b", &b"data"[..], QoS::AtLeastOnce)).unwrap(),
+            Some(1)
+        );
+
+        // the offline queue rejects them too
+        session.connection_lost(Instant::now());
+
+        assert!(invalid(session.publish(Message::new("a/#", &b"data"[..], QoS::AtMostOnce))));
+        assert_eq!(session.queued(), 0);
+    }
+
+    #[test]
+    fn test_session_subscribe() {
+        let mut session = connected();
+
+        assert_eq!(
+            session
+                .subscribe(&[("a/+", QoS::AtLeastOnce), ("b/#", QoS::ExactlyOnce)])
+                .unwrap(),
+            1
+        );
+        assert_eq!(session.unsubscribe(&["c"]).unwrap(), 2);
+        assert_eq!(
+            drain(&mut session)
+                .iter()
+                .map(|packet| packet.packet().packet_type())
+                .collect::<Vec<_>>(),
+            vec![SUBSCRIBE, UNSUBSCRIBE]
+        );
+
+        session
+            .handle_packet(
+                &Packet::SubscribeAck {
+                    packet_id: 1,
+                    status: vec![
+                        SubscribeReturnCode::Success(QoS::AtLeastOnce),
+                        SubscribeReturnCode::Failure,
+                    ],
+                },
+                Instant::now(),
+            )
+            .unwrap();
+        session
+            .handle_packet(&Packet::UnsubscribeAck { packet_id: 2 }, Instant::now())
+            .unwrap();
+
+        assert_eq!(
+            session.poll_event(),
+            Some(Event::Subscribed {
+                packet_id: 1,
+                status: vec![
+                    ("a/+".to_owned(), SubscribeReturnCode::Success(QoS::AtLeastOnce)),
+                    ("b/#".to_owned(), SubscribeReturnCode::Failure),
+                ],
+            })
+        );
+        assert_eq!(
+            session.poll_event(),
+            Some(Event::Unsubscribed {
+                packet_id: 2,
+                topic_filters: vec!["c".to_owned()],
+            })
+        );
+        assert_eq!(session.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_session_timeout() {
+        let mut session = connected();
+        let now = Instant::now();
+
+        session.set_ack_timeout(Some(Duration::from_secs(5)));
+        session.subscribe(&[("topic", QoS::AtLeastOnce)]).unwrap();
+
+        session.handle_tick(now).unwrap();
+        session.handle_tick(now + Duration::from_secs(4)).unwrap();
+
+        assert_eq!(session.poll_event(), None);
+
+        session.handle_tick(now + Duration::from_secs(5)).unwrap();
+
+        assert_eq!(session.poll_event(), Some(Event::Timeout(1)));
+        assert_eq!(session.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_session_reset() {
+        let mut session = connected();
+
+        session.subscribe(&[("topic", QoS::AtLeastOnce)]).unwrap();
+        session.publish(Message::new("topic", &b"data"[..], QoS::AtLeastOnce)).unwrap();
+        session.publish(Message::new("topic", &b"data"[..], QoS::ExactlyOnce)).unwrap();
+
+        session.reset().unwrap();
+
+        // only the dropped messages are reported
+        assert_eq!(session.poll_event(), Some(Event::PublishFailed(2)));
+        assert_eq!(session.poll_event(), Some(Event::PublishFailed(3)));
+        assert_eq!(session.poll_event(), None);
+        assert_eq!(session.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_session_retry() {
+        let mut session = Session::new();
+        let now = Instant::now();
+        let secs = |n| now + Duration::from_secs(n);
+
+        session.connect(ConnectOptions {
+            retry: Some(RetryPolicy {
+                interval: Duration::from_secs(10),
+                max_retries: Some(1),
+            }),
+            ..Default::default()
+        }).unwrap();
+        session
+            .handle_packet(
+                &Packet::ConnectAck {
+                    session_present: false,
+                    return_code: ConnectReturnCode::ConnectionAccepted,
+                },
+                now,
+            )
+            .unwrap();
+        session.poll_event();
+
+        session
+            .publish(Message::new("topic", &b"data"[..], QoS::AtLeastOnce))
+            .unwrap();
+        session
+            .publish(Message::new("topic", &b"data"[..], QoS::ExactlyOnce))
+            .unwrap();
+        drain(&mut session);
+        session.handle_tick(now).unwrap();
+
+        assert_eq!(session.next_tick(), Some(secs(10)));
+
+        // unacknowledged messages are resent with DUP
+        session.handle_tick(secs(10)).unwrap();
+
+        let resent = drain(&mut session);
+
+        assert_eq!(resent.len(), 2);
+        assert!(resent.iter().all(|packet| match *packet {
+            Outgoing::Publish { dup, .. } => dup,
+            _ => false,
+        }));
+
+        // PUBREL is resent until PUBCOMP
+        session
+            .handle_packet(&Packet::PublishReceived { packet_id: 2 }, secs(10))
+            .unwrap();
+
+        assert_eq!(drain(&mut session), vec![Outgoing::PublishRelease(2)]);
+
+        session.handle_tick(secs(15)).unwrap();
+
+        // the message is failed once the retries are exhausted
+        session.handle_tick(secs(20)).unwrap();
+
+        assert_eq!(session.poll_event(), Some(Event::PublishFailed(1)));
+        assert_eq!(session.poll_packet(), None);
+        assert_eq!(session.in_flight(), 1);
+
+        session.handle_tick(secs(25)).unwrap();
+
+        assert_eq!(drain(&mut session), vec![Outgoing::PublishRelease(2)]);
+
+        session
+            .handle_packet(&Packet::PublishComplete { packet_id: 2 }, secs(30))
+            .unwrap();
+
+        assert_eq!(session.poll_event(), Some(Event::Published(2)));
+        assert_eq!(session.next_tick(), None);
+    }
+
+    #[test]
+    fn test_session_keep_alive() {
+        let mut session = Session::new();
+        let now = Instant::now();
+        let secs = |n| now + Duration::from_secs(n);
+
+        session.connect(ConnectOptions {
+            keep_alive: 10,
+            ping_timeout: Some(Duration::from_secs(3)),
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(session.keep_alive(), Some(Duration::from_secs(10)));
+        assert_eq!(session.next_tick(), None);
+
+        session.poll_packet().unwrap();
+        session
+            .handle_packet(
+                &Packet::ConnectAck {
+                    session_present: false,
+                    return_code: ConnectReturnCode::ConnectionAccepted,
+                },
+                now,
+            )
+            .unwrap();
+        session.handle_tick(now).unwrap();
+
+        assert_eq!(session.next_tick(), Some(secs(10)));
+
+        // any packet sent restarts the keep alive interval
+        session.handle_tick(secs(9)).unwrap();
+        session
+            .publish(Message::new("topic", &b"data"[..], QoS::AtMostOnce))
+            .unwrap();
+        session.poll_packet().unwrap();
+        session.handle_tick(secs(9)).unwrap();
+
+        assert_eq!(session.poll_packet(), None);
+        assert_eq!(session.next_tick(), Some(secs(19)));
+
+        session.handle_tick(secs(19)).unwrap();
+
+        assert_eq!(session.poll_packet(), Some(Outgoing::PingRequest));
+        assert_eq!(session.next_tick(), Some(secs(22)));
+
+        session
+            .handle_packet(&Packet::PingResponse, secs(20))
+            .unwrap();
+        session.handle_tick(secs(20)).unwrap();
+
+        assert_eq!(session.next_tick(), Some(secs(30)));
+
+        // a missing ping response means the connection is dead
+        session.handle_tick(secs(30)).unwrap();
+
+        assert_eq!(session.poll_packet(), Some(Outgoing::PingRequest));
+
+        session.handle_tick(secs(32)).unwrap();
+
+        assert_eq!(session.poll_event().unwrap(), Event::Connected { session_present: false });
+        assert_eq!(
+            session.poll_event(),
+            Some(Event::Pong { latency: Duration::from_secs(1) })
+        );
+        assert_eq!(session.poll_event(), None);
+
+        session.handle_tick(secs(33)).unwrap();
+
+        assert_eq!(session.state(), State::Disconnected);
+        assert_eq!(
+            session.poll_event(),
+            Some(Event::Disconnected(DisconnectReason::KeepAliveTimeout))
+        );
+        assert_eq!(session.next_tick(), None);
+    }
+
+    #[test]
+    fn test_session_packet_id() {
+        let mut session = connected();
+        let msg = Message::new("topic", &b"data"[..], QoS::AtLeastOnce);
+
+        assert_eq!(session.publish(msg.clone()).unwrap(), Some(1));
+
+        // an acknowledged id is not reused until the allocator wraps around
+        session.on_publish_ack(1).unwrap();
+
+        assert_eq!(session.publish(msg.clone()).unwrap(), Some(2));
+        assert_eq!(session.subscribe(&[("topic/#", QoS::AtLeastOnce)]).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_session_in_flight_limit() {
+        let mut session = connected();
+        let msg = Message::new("topic", &b"data"[..], QoS::ExactlyOnce);
+
+        for _ in 0..PacketId::MAX {
+            session.publish(msg.clone()).unwrap();
+        }
+
+        assert_eq!(session.in_flight(), 65535);
+        assert!(!session.waiting_reply.contains_key(&0));
+
+        match session.publish(msg.clone()) {
+            Err(Error(ErrorKind::PacketIdExhausted, _)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        session.on_publish_received(100).unwrap();
+        session.on_publish_complete(100).unwrap();
+
+        assert_eq!(session.publish(msg.clone()).unwrap(), Some(100));
+    }
+
+    #[test]
+    fn test_session_in_flight_window() {
+        let mut session = Session::new();
+        let msg = |qos| Message::new("topic", &b"data"[..], qos);
+
+        session.connect(ConnectOptions {
+            max_in_flight: Some(2),
+            ..Default::default()
+        }).unwrap();
+        session
+            .handle_packet(
+                &Packet::ConnectAck {
+                    session_present: false,
+                    return_code: ConnectReturnCode::ConnectionAccepted,
+                },
+                Instant::now(),
+            )
+            .unwrap();
+
+        assert_eq!(session.publish(msg(QoS::AtLeastOnce)).unwrap(), Some(1));
+        assert_eq!(session.publish(msg(QoS::ExactlyOnce)).unwrap(), Some(2));
+        assert!(session.is_window_full());
+
+        // a full window never downgrades the message
+        match session.publish(msg(QoS::AtLeastOnce)) {
+            Err(Error(ErrorKind::WouldBlock, _)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        // QoS 0 messages and subscriptions don't take a slot
+        assert_eq!(session.publish(msg(QoS::AtMostOnce)).unwrap(), None);
+        assert_eq!(session.subscribe(&[("topic", QoS::AtLeastOnce)]).unwrap(), 3);
+
+        // a QoS 2 message holds its slot until PUBCOMP
+        session.on_publish_received(2).unwrap();
+
+        assert!(session.is_window_full());
+
+        session.on_publish_ack(1).unwrap();
+
+        assert!(!session.is_window_full());
+        assert_eq!(session.publish(msg(QoS::AtLeastOnce)).unwrap(), Some(4));
+    }
+
+    #[test]
+    fn test_session_resend_on_connect() {
+        let mut session = Session::new();
+
+        // requests made while disconnected are only sent once connected
+        session
+            .publish(Message::new("topic", &b"data"[..], QoS::AtLeastOnce))
+            .unwrap();
+        session.subscribe(&[("topic", QoS::ExactlyOnce)]).unwrap();
+        session
+            .publish(Message::new("topic", &b"data"[..], QoS::AtMostOnce))
+            .unwrap();
+
+        assert_eq!(session.queued(), 2);
+        assert_eq!(session.poll_packet(), None);
+
+        // the session, with its in-flight state, may change threads
+        let mut session = thread::spawn(move || connected_from(session))
+            .join()
+            .unwrap();
+
+        assert_eq!(
+            drain(&mut session)
+                .iter()
+                .map(|packet| packet.packet().packet_type())
+                .collect::<Vec<_>>(),
+            vec![SUBSCRIBE, PUBLISH, PUBLISH]
+        );
+        assert_eq!(session.queued(), 0);
+
+        session.connection_lost(Instant::now());
+
+        assert_eq!(
+            session.poll_event(),
+            Some(Event::Disconnected(DisconnectReason::ConnectionLost))
+        );
+        assert_eq!(session.in_flight(), 2);
+        assert!(!session.is_reconnecting());
+        assert_eq!(session.next_tick(), None);
+
+        // the message was written before the connection was lost, even without a clock tick
+        let mut session = connected_from(session);
+
+        assert_eq!(
+            drain(&mut session),
+            vec![
+                Outgoing::Publish {
+                    dup: true,
+                    retain: false,
+                    packet_id: Some(1),
+                    msg: Message::new("topic", &b"data"[..], QoS::AtLeastOnce),
+                },
+                Outgoing::Subscribe {
+                    packet_id: 2,
+                    topic_filters: vec![("topic".to_owned(), QoS::ExactlyOnce)],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_session_offline_queue() {
+        let offline = |overflow| {
+            let mut session = Session::new();
+
+            session.connect(ConnectOptions {
+                offline_queue: OfflineQueue {
+                    max_messages: 3,
+                    max_bytes: 32,
+                    overflow,
+                },
+                ..Default::default()
+            }).unwrap();
+            session.poll_packet();
+
+            session
+        };
+        let publish = |session: &mut Session, payload: &'static str, qos| {
+            session.publish(Message::new("topic", payload, qos))
+        };
+        let payloads = |session: &mut Session| {
+            session
+                .handle_packet(
+                    &Packet::ConnectAck {
+                        session_present: false,
+                        return_code: ConnectReturnCode::ConnectionAccepted,
+                    },
+                    Instant::now(),
+                )
+                .unwrap();
+
+            drain(session)
+                .into_iter()
+                .map(|packet| match packet {
+                    Outgoing::Publish { msg, .. } => msg.payload,
+                    packet => panic!("unexpected packet {:?}", packet),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // reject new messages, bounded by count and bytes
+        let mut session = offline(OverflowPolicy::RejectNew);
+
+        assert_eq!(publish(&mut session, "1", QoS::AtMostOnce).unwrap(), None);
+        assert_eq!(publish(&mut session, "2", QoS::AtLeastOnce).unwrap(), Some(1));
+        assert_eq!(publish(&mut session, "3", QoS::AtMostOnce).unwrap(), None);
+
+        match publish(&mut session, "4", QoS::AtMostOnce) {
+            Err(Error(ErrorKind::QueueFull, _)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        assert!(publish(&mut session, "a payload larger than the queue", QoS::AtMostOnce).is_err());
+        assert_eq!(payloads(&mut session), vec!["1", "2", "3"]);
+        assert_eq!(session.in_flight(), 1);
+
+        // drop the oldest message, failing it if it has a packet id
+        let mut session = offline(OverflowPolicy::DropOldest);
+
+        publish(&mut session, "1", QoS::AtLeastOnce).unwrap();
+        publish(&mut session, "2", QoS::AtMostOnce).unwrap();
+        publish(&mut session, "3", QoS::AtMostOnce).unwrap();
+        publish(&mut session, "4", QoS::AtMostOnce).unwrap();
+        publish(&mut session, "twenty bytes payload", QoS::AtMostOnce).unwrap();
+
+        assert_eq!(session.poll_event(), Some(Event::PublishFailed(1)));
+        assert_eq!(session.queued(), 2);
+        assert_eq!(payloads(&mut session), vec!["4", "twenty bytes payload"]);
+        assert_eq!(session.in_flight(), 0);
+
+        // drop QoS 0 messages first
+        let mut session = offline(OverflowPolicy::DropQoS0First);
+
+        publish(&mut session, "1", QoS::AtLeastOnce).unwrap();
+        publish(&mut session, "2", QoS::AtMostOnce).unwrap();
+        publish(&mut session, "3", QoS::ExactlyOnce).unwrap();
+        publish(&mut session, "4", QoS::AtLeastOnce).unwrap();
+
+        assert!(publish(&mut session, "5", QoS::AtMostOnce).is_err());
+        assert_eq!(session.poll_event(), None);
+        assert_eq!(payloads(&mut session), vec!["1", "3", "4"]);
+        assert_eq!(session.in_flight(), 3);
+    }
+
+    #[test]
+    fn test_reconnect_policy() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            jitter: 0.0,
+            max_attempts: None,
+        };
+
+        assert_eq!(policy.delay(0), Duration::from_secs(1));
+        assert_eq!(policy.delay(1), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(8));
+        assert_eq!(policy.delay(4), Duration::from_secs(10));
+        assert_eq!(policy.delay(100), Duration::from_secs(10));
+
+        let policy = ReconnectPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+
+            assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn test_session_reconnect() {
+        let mut session = Session::new();
+        let now = Instant::now();
+
+        session.connect(ConnectOptions {
+            clean_session: false,
+            reconnect: Some(ReconnectPolicy {
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(10),
+                jitter: 0.0,
+                max_attempts: Some(2),
+            }),
+            ..Default::default()
+        }).unwrap();
+        session
+            .handle_packet(
+                &Packet::ConnectAck {
+                    session_present: false,
+                    return_code: ConnectReturnCode::ConnectionAccepted,
+                },
+                now,
+            )
+            .unwrap();
+
+        let packet_id = session.subscribe(&[("topic", QoS::AtLeastOnce)]).unwrap();
+
+        session
+            .handle_packet(
+                &Packet::SubscribeAck {
+                    packet_id,
+                    status: vec![SubscribeReturnCode::Success(QoS::AtLeastOnce)],
+                },
+                now,
+            )
+            .unwrap();
+        session
+            .publish(Message::new("topic", &b"data"[..], QoS::AtLeastOnce))
+            .unwrap();
+        drain(&mut session);
+        session.handle_tick(now).unwrap();
+        while session.poll_event().is_some() {}
+
+        // the first attempt is scheduled after the initial delay
+        session.connection_lost(now);
+
+        assert_eq!(
+            session.poll_event(),
+            Some(Event::Disconnected(DisconnectReason::ConnectionLost))
+        );
+        assert!(session.is_reconnecting());
+        assert_eq!(session.next_tick(), Some(now + Duration::from_secs(1)));
+
+        session.handle_tick(now + Duration::from_millis(500)).unwrap();
+
+        assert_eq!(session.poll_event(), None);
+
+        session.handle_tick(now + Duration::from_secs(1)).unwrap();
+
+        assert_eq!(session.poll_event(), Some(Event::Reconnect { attempt: 1 }));
+
+        session.reconnect().unwrap();
+
+        assert_eq!(session.state(), State::Connecting);
+        assert_eq!(session.poll_packet().unwrap().packet().packet_type(), CONNECT);
+
+        // a failed attempt doubles the delay
+        let now = now + Duration::from_secs(1);
+
+        session.connection_lost(now);
+        session.poll_event();
+
+        assert_eq!(session.next_tick(), Some(now + Duration::from_secs(2)));
+
+        session.handle_tick(now + Duration::from_secs(2)).unwrap();
+
+        assert_eq!(session.poll_event(), Some(Event::Reconnect { attempt: 2 }));
+
+        // the in-flight message is replayed and the subscriptions restored
+        session.reconnect().unwrap();
+        drain(&mut session);
+        session
+            .handle_packet(
+                &Packet::ConnectAck {
+                    session_present: false,
+                    return_code: ConnectReturnCode::ConnectionAccepted,
+                },
+                now,
+            )
+            .unwrap();
+
+        assert_eq!(
+            session.poll_event(),
+            Some(Event::Connected { session_present: false })
+        );
+        assert!(!session.is_reconnecting());
+
+        match drain(&mut session).as_slice() {
+            [Outgoing::Publish {
+                dup: true,
+                packet_id: Some(_),
+                ..
+            },
+             Outgoing::Subscribe { topic_filters, .. }] => {
+                assert_eq!(topic_filters, &[("topic".to_owned(), QoS::AtLeastOnce)])
+            }
+            packets => panic!("unexpected packets {:?}", packets),
+        }
+
+        // give up once the attempts are exhausted
+        for attempt in 1..3 {
+            session.connection_lost(now);
+            session.poll_event();
+            session.handle_tick(now + Duration::from_secs(10)).unwrap();
+
+            assert_eq!(session.poll_event(), Some(Event::Reconnect { attempt }));
+
+            session.reconnect().unwrap();
+        }
+
+        session.connection_lost(now);
+
+        assert_eq!(
+            session.poll_event(),
+            Some(Event::Disconnected(DisconnectReason::ConnectionLost))
+        );
+        assert_eq!(session.poll_event(), Some(Event::ReconnectFailed { attempts: 2 }));
+        assert!(!session.is_reconnecting());
+        assert_eq!(session.next_tick(), None);
+    }
+
+    fn connected_from(mut session: Session) -> Session {
+        session.connect(ConnectOptions {
+            clean_session: false,
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(session.poll_packet().unwrap().packet().packet_type(), CONNECT);
+
+        session
+            .handle_packet(
+                &Packet::ConnectAck {
+                    session_present: true,
+                    return_code: ConnectReturnCode::ConnectionAccepted,
+                },
+                Instant::now(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            session.poll_event(),
+            Some(Event::Connected { session_present: true })
+        );
+
+        session
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        sent: Vec<u8>,
+        published: Vec<Option<PacketId>>,
+        failed: Vec<(Option<PacketId>, DeliveryFailure)>,
+        connected: Vec<bool>,
+        refused: Vec<ConnectReturnCode>,
+        disconnected: Vec<DisconnectReason>,
+        pongs: usize,
+        received: Vec<String>,
+        connect: Vec<u8>,
+        reconnects: usize,
+    }
+
+    struct MockTransport(Arc<::std::sync::Mutex<Recorder>>);
+
+    impl Transport for MockTransport {
+        fn send_packet(&mut self, packet: &Packet) -> Result<()> {
+            let mut recorder = self.0.lock().unwrap();
+
+            recorder.sent.push(packet.packet_type());
+
+            if packet.packet_type() == CONNECT {
+                recorder.connect.clear();
+                recorder.connect.write_packet(packet)?;
+            }
+
+            Ok(())
+        }
+
+        fn reconnect(&mut self) -> Result<()> {
+            self.0.lock().unwrap().reconnects += 1;
+
+            Ok(())
+        }
+    }
+
+    struct MockHandler(Arc<::std::sync::Mutex<Recorder>>);
+
+    impl Handler for MockHandler {
+        fn on_received_message(&mut self, msg: &Message) {
+            self.0.lock().unwrap().received.push(msg.topic.clone());
+        }
+
+        fn on_subscribed_topic(&mut self, _: &[(&str, SubscribeReturnCode)]) {}
+
+        fn on_unsubscribed_topic(&mut self, _: &[&str]) {}
+
+        fn on_published(&mut self, token: &DeliveryToken) {
+            self.0.lock().unwrap().published.push(token.packet_id());
+        }
+
+        fn on_publish_failed(&mut self, token: &DeliveryToken, reason: DeliveryFailure) {
+            self.0.lock().unwrap().failed.push((token.packet_id(), reason));
+        }
+
+        fn on_connected(&mut self, session_present: bool) {
+            self.0.lock().unwrap().connected.push(session_present);
+        }
+
+        fn on_connect_refused(&mut self, return_code: ConnectReturnCode) {
+            self.0.lock().unwrap().refused.push(return_code);
+        }
+
+        fn on_disconnected(&mut self, reason: DisconnectReason) {
+            self.0.lock().unwrap().disconnected.push(reason);
+        }
+
+        fn on_pong(&mut self, _: Duration) {
+            self.0.lock().unwrap().pongs += 1;
+        }
+    }
+
+    #[test]
+    fn test_client_subscribe_with() {
+        let recorder = Arc::new(::std::sync::Mutex::new(Recorder::default()));
+        let mut client = Builder::default()
+            .build(MockTransport(recorder.clone()), MockHandler(recorder.clone()));
+        let received = Arc::new(::std::sync::Mutex::new(vec![]));
+        let callback = |name: &'static str| {
+            let received = received.clone();
+
+            move |msg: &Message| received.lock().unwrap().push((name, msg.topic.clone()))
+        };
+        let publish = |client: &mut Client<_, _>, topic: &str| {
+            transport::Handler::on_received_packet(
+                client,
+                &Packet::Publish {
+                    dup: false,
+                    retain: false,
+                    qos: QoS::AtMostOnce,
+                    topic,
+                    packet_id: None,
+                    payload: b"data",
+                },
+            )
+        };
+        let suback = |client: &mut Client<_, _>, packet_id, code| {
+            transport::Handler::on_received_packet(
+                client,
+                &Packet::SubscribeAck {
+                    packet_id,
+                    status: vec![code],
+                },
+            )
+        };
+
+        client.connect().unwrap();
+        transport::Handler::on_received_packet(
+            &mut client,
+            &Packet::ConnectAck {
+                session_present: false,
+                return_code: ConnectReturnCode::ConnectionAccepted,
+            },
+        );
+
+        assert!(client.subscribe_with("sport/#/+", QoS::AtMostOnce, callback("bad")).is_err());
+
+        let granted = SubscribeReturnCode::Success(QoS::AtMostOnce);
+        let sport = client.subscribe_with("sport/#", QoS::AtMostOnce, callback("sport")).unwrap();
+
+        // the callback waits for SUBACK
+        publish(&mut client, "sport/golf");
+        suback(&mut client, sport, granted);
+
+        let tennis = client
+            .subscribe_with("sport/tennis/+", QoS::AtMostOnce, callback("tennis"))
+            .unwrap();
+        let player = client
+            .subscribe_with("sport/tennis/+", QoS::AtMostOnce, callback("player"))
+            .unwrap();
+        let news = client.subscribe_with("news", QoS::AtMostOnce, callback("news")).unwrap();
+
+        suback(&mut client, tennis, granted);
+        suback(&mut client, player, granted);
+        // a refused subscription never gets its callback
+        suback(&mut client, news, SubscribeReturnCode::Failure);
+
+        // overlapping filters all receive the message
+        publish(&mut client, "sport/tennis/player1");
+        publish(&mut client, "sport/golf");
+        publish(&mut client, "news");
+
+        let packet_id = client.unsubscribe(&["sport/#"]).unwrap();
+
+        // the callbacks are kept until UNSUBACK
+        publish(&mut client, "sport/golf");
+        transport::Handler::on_received_packet(&mut client, &Packet::UnsubscribeAck { packet_id });
+
+        publish(&mut client, "sport/tennis/player2");
+        publish(&mut client, "sport/golf");
+
+        let mut received = received.lock().unwrap().clone();
+
+        received[..3].sort();
+
+        assert_eq!(
+            received,
+            vec![
+                ("player", "sport/tennis/player1".to_owned()),
+                ("sport", "sport/tennis/player1".to_owned()),
+                ("tennis", "sport/tennis/player1".to_owned()),
+                ("sport", "sport/golf".to_owned()),
+                ("sport", "sport/golf".to_owned()),
+                ("tennis", "sport/tennis/player2".to_owned()),
+                ("player", "sport/tennis/player2".to_owned()),
+            ]
+        );
+        assert_eq!(
+            recorder.lock().unwrap().received,
+            vec!["sport/golf", "news", "sport/golf"]
+        );
+    }
+
+    #[test]
+    fn test_client_connect_options() {
+        let recorder = Arc::new(::std::sync::Mutex::new(Recorder::default()));
+        let builder = Builder::default()
+            .client_id(ClientId::from("gateway"))
+            .protocol(Protocol::MQTT(4))
+            .clean_session(false)
+            .keep_alive(Duration::from_secs(1 << 20))
+            .username("user")
+            .password("pass")
+            .last_will(Message::new("status", "offline", QoS::AtLeastOnce), true);
+
+        assert_eq!(builder.options().keep_alive, u16::MAX);
+
+        let mut client =
+            builder.build(MockTransport(recorder.clone()), MockHandler(recorder.clone()));
+
+        client.connect().unwrap();
+
+        let recorder = recorder.lock().unwrap();
+
+        assert_eq!(recorder.sent, vec![CONNECT]);
+        assert_eq!(
+            read_packet(&recorder.connect).unwrap().1,
+            Packet::Connect {
+                protocol: Protocol::MQTT(4),
+                clean_session: false,
+                keep_alive: u16::MAX,
+                last_will: Some(LastWill {
+                    qos: QoS::AtLeastOnce,
+                    retain: true,
+                    topic: "status",
+                    message: b"offline",
+                }),
+                client_id: "gateway",
+                username: Some("user"),
+                password: Some(b"pass"),
+            }
+        );
+    }
+
+    #[test]
+    fn test_client_lifecycle() {
+        let recorder = Arc::new(::std::sync::Mutex::new(Recorder::default()));
+        let mut client = Builder::default()
+            .build(MockTransport(recorder.clone()), MockHandler(recorder.clone()));
+
+        client.connect().unwrap();
+        transport::Handler::on_received_packet(
+            &mut client,
+            &Packet::ConnectAck {
+                session_present: true,
+                return_code: ConnectReturnCode::ConnectionAccepted,
+            },
+        );
+        client.ping().unwrap();
+        transport::Handler::on_received_packet(&mut client, &Packet::PingResponse);
+        client.disconnect().unwrap();
+
+        client.connect().unwrap();
+        transport::Handler::on_received_packet(
+            &mut client,
+            &Packet::ConnectAck {
+                session_present: false,
+                return_code: ConnectReturnCode::NotAuthorized,
+            },
+        );
+
+        let recorder = recorder.lock().unwrap();
+
+        assert_eq!(recorder.sent, vec![CONNECT, PINGREQ, DISCONNECT, CONNECT]);
+        assert_eq!(recorder.connected, vec![true]);
+        assert_eq!(recorder.pongs, 1);
+        assert_eq!(recorder.disconnected, vec![DisconnectReason::Requested]);
+        assert_eq!(recorder.refused, vec![ConnectReturnCode::NotAuthorized]);
+    }
+
+    #[test]
+    fn test_client_reconnect() {
+        let recorder = Arc::new(::std::sync::Mutex::new(Recorder::default()));
+        let mut client = Builder::default()
+            .clean_session(false)
+            .reconnect(ReconnectPolicy {
+                initial_delay: Duration::from_secs(0),
+                jitter: 0.0,
+                ..Default::default()
+            })
+            .build(MockTransport(recorder.clone()), MockHandler(recorder.clone()));
+        let connack = |client: &mut Client<_, _>| {
+            transport::Handler::on_received_packet(
+                client,
+                &Packet::ConnectAck {
+                    session_present: true,
+                    return_code: ConnectReturnCode::ConnectionAccepted,
+                },
+            )
+        };
+
+        client.connect().unwrap();
+        connack(&mut client);
+
+        let token = client.publish(Message::new("topic", &b"data"[..], QoS::AtLeastOnce)).unwrap();
+
+        // the transport reports the lost connection, and the next tick reconnects it
+        client.connection_lost();
+        client.tick().unwrap();
+        connack(&mut client);
+        transport::Handler::on_received_packet(&mut client, &Packet::PublishAck { packet_id: 1 });
+
+        assert_eq!(token.state(), DeliveryState::Published);
+
+        let recorder = recorder.lock().unwrap();
+
+        assert_eq!(recorder.reconnects, 1);
+        assert_eq!(recorder.sent, vec![CONNECT, PUBLISH, CONNECT, PUBLISH]);
+        assert_eq!(recorder.connected, vec![true, true]);
+        assert_eq!(recorder.disconnected, vec![DisconnectReason::ConnectionLost]);
+    }
+
+    #[test]
+    fn test_client_delivery_token() {
+        let recorder = Arc::new(::std::sync::Mutex::new(Recorder::default()));
+        let mut client = Builder::default()
+            .retry(RetryPolicy {
+                interval: Duration::from_secs(0),
+                max_retries: Some(0),
+            })
+            .build(MockTransport(recorder.clone()), MockHandler(recorder.clone()));
+        let msg = |qos| Message::new("topic", &b"data"[..], qos);
+
+        client.connect().unwrap();
+        transport::Handler::on_received_packet(
+            &mut client,
+            &Packet::ConnectAck {
+                session_present: false,
+                return_code: ConnectReturnCode::ConnectionAccepted,
+            },
+        );
+
+        let token = client.publish(msg(QoS::AtMostOnce)).unwrap();
+
+        assert_eq!(token.packet_id(), None);
+        assert_eq!(token.state(), DeliveryState::Published);
+
+        let token = client.publish(msg(QoS::AtLeastOnce)).unwrap();
+
+        assert_eq!(token.packet_id(), Some(1));
+        assert_eq!(token.state(), DeliveryState::Pending);
+
+        transport::Handler::on_received_packet(&mut client, &Packet::PublishAck { packet_id: 1 });
+
+        assert_eq!(token.state(), DeliveryState::Published);
+        assert!(token.wait().is_ok());
+
+        // the retry policy gives up on a message never acknowledged
+        let token = client.publish(msg(QoS::ExactlyOnce)).unwrap();
+
+        client.tick().unwrap();
+
+        assert_eq!(
+            token.state(),
+            DeliveryState::Failed(DeliveryFailure::Abandoned)
+        );
+
+        let recorder = recorder.lock().unwrap();
+
+        assert_eq!(recorder.sent, vec![CONNECT, PUBLISH, PUBLISH, PUBLISH]);
+        assert_eq!(recorder.published, vec![Some(1)]);
+        assert_eq!(recorder.failed, vec![(Some(2), DeliveryFailure::Abandoned)]);
+    }
+
+    #[test]
+    fn test_client_clean_session_abandons() {
+        let recorder = Arc::new(::std::sync::Mutex::new(Recorder::default()));
+        let mut client = Builder::default()
+            .build(MockTransport(recorder.clone()), MockHandler(recorder.clone()));
+
+        client.connect().unwrap();
+        transport::Handler::on_received_packet(
+            &mut client,
+            &Packet::ConnectAck {
+                session_present: false,
+                return_code: ConnectReturnCode::ConnectionAccepted,
+            },
+        );
+
+        let token = client.publish(Message::new("topic", &b"data"[..], QoS::AtLeastOnce)).unwrap();
+
+        // the client reconnects before PUBACK, and the new clean session drops the message
+        client.connect().unwrap();
+
+        assert_eq!(
+            token.state(),
+            DeliveryState::Failed(DeliveryFailure::Abandoned)
+        );
+        assert_eq!(client.session().in_flight(), 0);
+
+        let recorder = recorder.lock().unwrap();
+
+        assert_eq!(recorder.sent, vec![CONNECT, PUBLISH, CONNECT]);
+        assert_eq!(recorder.failed, vec![(Some(1), DeliveryFailure::Abandoned)]);
+    }
 }
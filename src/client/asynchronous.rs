@@ -0,0 +1,838 @@
+//! An asynchronous MQTT client running on the tokio runtime.
+//!
+//! The connection is driven by an event loop task spawned on the current runtime,
+//! which sends acknowledgments and keep-alive pings on its own and reconnects following
+//! `ConnectOptions::reconnect`; `AsyncClient` only forwards requests to it and may be
+//! cloned freely.
+//!
+//! ```no_run
+//! # extern crate mqtt;
+//! # extern crate tokio;
+//! use mqtt::{Message, QoS};
+//! use mqtt::client::ConnectOptions;
+//! use mqtt::client::asynchronous::AsyncClient;
+//!
+//! # fn main() {
+//! let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+//! let (client, mut messages) = rt.block_on(AsyncClient::connect("localhost:1883",
+//!                                                               ConnectOptions::default()))
+//!     .unwrap();
+//!
+//! rt.block_on(client.subscribe(&[("sensor/+/temp", QoS::AtLeastOnce)])).unwrap();
+//! rt.block_on(client.publish(Message::new("sensor/1/temp", "21.5", QoS::AtLeastOnce)))
+//!     .unwrap();
+//!
+//! let msg = rt.block_on(messages.recv());
+//! # }
+//! ```
+use std::cmp;
+use std::mem;
+use std::io;
+use std::pin::Pin;
+use std::future::Future;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, VecDeque};
+
+use nom::IError;
+
+use futures_core::Stream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Sleep};
+
+use error::*;
+use proto::*;
+use packet::*;
+use decode::read_packet;
+use encode::WritePacketExt;
+use client::{Builder, ConnectOptions, DisconnectReason, Event, Session};
+
+const READ_BUF_SIZE: usize = 8 * 1024;
+
+// the longest time between two ticks of the session clock
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+type Responder<T> = oneshot::Sender<Result<T>>;
+
+enum Command {
+    Publish(Message, Responder<()>),
+    Subscribe(Vec<(String, QoS)>, Responder<Vec<SubscribeReturnCode>>),
+    Unsubscribe(Vec<String>, Responder<()>),
+    Disconnect(Responder<()>),
+}
+
+enum Pending {
+    Publish(Responder<()>),
+    Subscribe(Responder<Vec<SubscribeReturnCode>>),
+    Unsubscribe(Responder<()>),
+}
+
+impl Pending {
+    fn fail(self, err: Error) {
+        match self {
+            Pending::Publish(tx) | Pending::Unsubscribe(tx) => {
+                let _ = tx.send(Err(err));
+            }
+            Pending::Subscribe(tx) => {
+                let _ = tx.send(Err(err));
+            }
+        }
+    }
+}
+
+/// A handle to the connection, cheap to clone and share between tasks.
+#[derive(Clone, Debug)]
+pub struct AsyncClient {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl AsyncClient {
+    /// Connect to the server, resolving once the session has been accepted.
+    ///
+    /// The returned future spawns the event loop on the current tokio runtime.
+    pub fn connect<A>(addr: A, opts: ConnectOptions) -> Connect
+    where
+        A: ToSocketAddrs + Clone + Send + Sync + 'static,
+    {
+        AsyncClient::resume(addr, Session::new(), opts)
+    }
+
+    /// Connect to the server named by a connection URL, see `Builder::from_url`.
+    pub fn connect_url(url: &str) -> Result<Connect> {
+        AsyncClient::with_builder(Builder::from_url(url)?)
+    }
+
+    /// Connect to the endpoint of the builder, with its session and options.
+    ///
+    /// Only `mqtt` endpoints are supported, the others fail with `InvalidUrl`.
+    pub fn with_builder(builder: Builder) -> Result<Connect> {
+        let (addr, session, opts) = builder.into_tcp()?;
+
+        Ok(AsyncClient::resume(addr, session, opts))
+    }
+
+    /// Connect to the server, resuming a session usually restored with `Session::with_store`.
+    pub fn resume<A>(addr: A, session: Session, opts: ConnectOptions) -> Connect
+    where
+        A: ToSocketAddrs + Clone + Send + Sync + 'static,
+    {
+        let (commands, rx) = mpsc::unbounded_channel();
+        let (messages, incoming) = mpsc::unbounded_channel();
+        let (connected, established) = oneshot::channel();
+        let open: Box<dyn Fn() -> ConnectFuture + Send> =
+            Box::new(move || Box::pin(TcpStream::connect(addr.clone())));
+
+        let event_loop = EventLoop {
+            conn: Conn::Connecting(open()),
+            open,
+            opts: Some(opts),
+            session,
+            commands: rx,
+            messages,
+            connected: Some(connected),
+            disconnected: None,
+            pending: HashMap::new(),
+            blocked: VecDeque::new(),
+            read_buf: Vec::with_capacity(READ_BUF_SIZE),
+            write_buf: vec![],
+            timer: None,
+        };
+
+        Connect {
+            event_loop: Some(event_loop),
+            client: Some((AsyncClient { commands }, Messages { incoming })),
+            established,
+        }
+    }
+
+    /// Publish a message, resolving once the QoS 1 or QoS 2 handshake completes.
+    ///
+    /// While the in-flight window is full, the message waits for a free slot.
+    pub fn publish(&self, msg: Message) -> Reply<()> {
+        self.request(|tx| Command::Publish(msg, tx))
+    }
+
+    /// Subscribe to the topic filters, resolving to the status granted to each of them.
+    pub fn subscribe(&self, topic_filters: &[(&str, QoS)]) -> Reply<Vec<SubscribeReturnCode>> {
+        let topic_filters = topic_filters
+            .iter()
+            .map(|&(filter, qos)| (filter.to_owned(), qos))
+            .collect();
+
+        self.request(|tx| Command::Subscribe(topic_filters, tx))
+    }
+
+    pub fn unsubscribe(&self, topic_filters: &[&str]) -> Reply<()> {
+        let topic_filters = topic_filters.iter().map(|&filter| filter.to_owned()).collect();
+
+        self.request(|tx| Command::Unsubscribe(topic_filters, tx))
+    }
+
+    /// Send DISCONNECT and stop the event loop once it has been written.
+    pub fn disconnect(&self) -> Reply<()> {
+        self.request(Command::Disconnect)
+    }
+
+    fn request<T, F>(&self, command: F) -> Reply<T>
+    where
+        F: FnOnce(Responder<T>) -> Command,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        // a closed event loop drops the responder, which resolves the reply with an error
+        let _ = self.commands.send(command(tx));
+
+        Reply { rx }
+    }
+}
+
+/// A future resolving to the client and its incoming message stream.
+pub struct Connect {
+    event_loop: Option<EventLoop>,
+    client: Option<(AsyncClient, Messages)>,
+    established: oneshot::Receiver<Result<()>>,
+}
+
+impl Future for Connect {
+    type Output = Result<(AsyncClient, Messages)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(event_loop) = self.event_loop.take() {
+            tokio::spawn(event_loop);
+        }
+
+        match Pin::new(&mut self.established).poll(cx) {
+            Poll::Ready(Ok(Ok(()))) => {
+                Poll::Ready(Ok(self.client.take().expect("polled after completion")))
+            }
+            Poll::Ready(Ok(Err(err))) => Poll::Ready(Err(err)),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(ErrorKind::ConnectionClosed.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A future resolving to the outcome of a request.
+#[derive(Debug)]
+pub struct Reply<T> {
+    rx: oneshot::Receiver<Result<T>>,
+}
+
+impl<T> Future for Reply<T> {
+    type Output = Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.rx).poll(cx) {
+            Poll::Ready(Ok(res)) => Poll::Ready(res),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(ErrorKind::ConnectionClosed.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// The stream of messages received from the server, ends when the connection closes.
+#[derive(Debug)]
+pub struct Messages {
+    incoming: mpsc::UnboundedReceiver<Message>,
+}
+
+impl Messages {
+    /// Wait for the next message, returns `None` once the connection is closed.
+    pub fn recv(&mut self) -> Recv<'_> {
+        Recv { messages: self }
+    }
+}
+
+impl Stream for Messages {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.incoming.poll_recv(cx)
+    }
+}
+
+/// A future resolving to the next message received from the server.
+#[derive(Debug)]
+pub struct Recv<'a> {
+    messages: &'a mut Messages,
+}
+
+impl<'a> Future for Recv<'a> {
+    type Output = Option<Message>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.messages.incoming.poll_recv(cx)
+    }
+}
+
+type ConnectFuture = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+
+enum Conn {
+    Connecting(ConnectFuture),
+    Connected(TcpStream),
+    // waiting for the next reconnect attempt
+    Waiting,
+    Closed,
+}
+
+// Drives the session over the connection until it is closed or disconnected.
+struct EventLoop {
+    conn: Conn,
+    open: Box<dyn Fn() -> ConnectFuture + Send>,
+    opts: Option<ConnectOptions>,
+    session: Session,
+    commands: mpsc::UnboundedReceiver<Command>,
+    messages: mpsc::UnboundedSender<Message>,
+    connected: Option<Responder<()>>,
+    disconnected: Option<Responder<()>>,
+    pending: HashMap<PacketId, Pending>,
+    // messages waiting for a free slot in the in-flight window
+    blocked: VecDeque<(Message, Responder<()>)>,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl Future for EventLoop {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+
+        loop {
+            match this.poll_step(cx) {
+                Ok(Poll::Ready(true)) => {}
+                Ok(Poll::Ready(false)) => {
+                    this.close(ErrorKind::ConnectionClosed.into());
+
+                    return Poll::Ready(());
+                }
+                Ok(Poll::Pending) => return Poll::Pending,
+                Err(err) => {
+                    warn!("event loop stopped, {}", err);
+
+                    this.close(err);
+
+                    return Poll::Ready(());
+                }
+            }
+        }
+    }
+}
+
+impl EventLoop {
+    // Polls every source once, returns `Ready(true)` if some progress was made,
+    // `Ready(false)` once the client has disconnected.
+    fn poll_step(&mut self, cx: &mut Context<'_>) -> Result<Poll<bool>> {
+        // the first connection fails fast, only a lost one is retried
+        let initial = self.connected.is_some();
+        let connected = match self.conn {
+            Conn::Connecting(ref mut connect) => {
+                match connect.as_mut().poll(cx) {
+                    Poll::Ready(Ok(stream)) => Some(stream),
+                    Poll::Ready(Err(err)) if initial => bail!(err),
+                    Poll::Ready(Err(err)) => {
+                        warn!("fail to reconnect, {}", err);
+
+                        self.lost();
+
+                        None
+                    }
+                    Poll::Pending if initial => return Ok(Poll::Pending),
+                    Poll::Pending => None,
+                }
+            }
+            Conn::Connected(_) | Conn::Waiting => None,
+            Conn::Closed => return Ok(Poll::Ready(false)),
+        };
+
+        if let Some(stream) = connected {
+            stream.set_nodelay(true)?;
+
+            self.conn = Conn::Connected(stream);
+
+            // a reconnect has already queued CONNECT with the stored options
+            if let Some(opts) = self.opts.take() {
+                self.session.connect(opts)?;
+                self.timer = Some(Box::pin(time::sleep(TICK_INTERVAL)));
+            }
+        }
+
+        let mut progress = self.poll_commands(cx)?;
+
+        progress |= self.poll_read(cx)?;
+        progress |= self.poll_timer(cx)?;
+
+        self.dispatch()?;
+
+        progress |= self.unblock();
+        progress |= self.poll_write(cx)?;
+
+        if self.disconnected.is_some() && self.write_buf.is_empty() {
+            if let Conn::Connected(ref mut stream) = self.conn {
+                let _ = Pin::new(stream).poll_shutdown(cx);
+            }
+
+            if let Some(tx) = self.disconnected.take() {
+                let _ = tx.send(Ok(()));
+            }
+
+            self.conn = Conn::Closed;
+
+            return Ok(Poll::Ready(false));
+        }
+
+        Ok(if progress {
+            Poll::Ready(true)
+        } else {
+            Poll::Pending
+        })
+    }
+
+    fn poll_commands(&mut self, cx: &mut Context<'_>) -> Result<bool> {
+        let mut progress = false;
+
+        while self.disconnected.is_none() {
+            let command = match self.commands.poll_recv(cx) {
+                Poll::Ready(Some(command)) => command,
+                Poll::Ready(None) => {
+                    // every client handle has been dropped
+                    let (tx, _) = oneshot::channel();
+
+                    Command::Disconnect(tx)
+                }
+                Poll::Pending => break,
+            };
+
+            progress = true;
+
+            match command {
+                Command::Publish(msg, tx) => {
+                    self.blocked.push_back((msg, tx));
+                    self.unblock();
+                }
+                Command::Subscribe(topic_filters, tx) => {
+                    let topic_filters = topic_filters
+                        .iter()
+                        .map(|&(ref filter, qos)| (filter.as_str(), qos))
+                        .collect::<Vec<_>>();
+
+                    match self.session.subscribe(&topic_filters) {
+                        Ok(packet_id) => {
+                            self.pending.insert(packet_id, Pending::Subscribe(tx));
+                        }
+                        Err(err) => {
+                            let _ = tx.send(Err(err));
+                        }
+                    }
+                }
+                Command::Unsubscribe(topic_filters, tx) => {
+                    let topic_filters = topic_filters
+                        .iter()
+                        .map(|filter| filter.as_str())
+                        .collect::<Vec<_>>();
+
+                    match self.session.unsubscribe(&topic_filters) {
+                        Ok(packet_id) => {
+                            self.pending.insert(packet_id, Pending::Unsubscribe(tx));
+                        }
+                        Err(err) => {
+                            let _ = tx.send(Err(err));
+                        }
+                    }
+                }
+                Command::Disconnect(tx) => {
+                    self.session.disconnect();
+                    self.disconnected = Some(tx);
+                }
+            }
+        }
+
+        Ok(progress)
+    }
+
+    // Publish the messages held back by a full in-flight window, in order.
+    fn unblock(&mut self) -> bool {
+        let mut progress = false;
+
+        while let Some((msg, tx)) = self.blocked.pop_front() {
+            match self.session.publish(msg.clone()) {
+                Err(Error(ErrorKind::WouldBlock, _)) => {
+                    self.blocked.push_front((msg, tx));
+
+                    break;
+                }
+                Ok(Some(packet_id)) => {
+                    self.pending.insert(packet_id, Pending::Publish(tx));
+                }
+                Ok(None) => {
+                    let _ = tx.send(Ok(()));
+                }
+                Err(err) => {
+                    let _ = tx.send(Err(err));
+                }
+            }
+
+            progress = true;
+        }
+
+        progress
+    }
+
+    fn poll_read(&mut self, cx: &mut Context<'_>) -> Result<bool> {
+        let mut progress = false;
+
+        let mut lost = false;
+
+        if let Conn::Connected(ref mut stream) = self.conn {
+            let mut data = [0; READ_BUF_SIZE];
+
+            loop {
+                let mut buf = ReadBuf::new(&mut data);
+
+                match Pin::new(&mut *stream).poll_read(cx, &mut buf) {
+                    Poll::Ready(Ok(())) if buf.filled().is_empty() => {
+                        debug!("connection closed by the server");
+
+                        lost = true;
+                        break;
+                    }
+                    Poll::Ready(Ok(())) => {
+                        self.read_buf.extend_from_slice(buf.filled());
+
+                        progress = true;
+                    }
+                    Poll::Ready(Err(err)) => {
+                        warn!("fail to read from the server, {}", err);
+
+                        lost = true;
+                        break;
+                    }
+                    Poll::Pending => break,
+                }
+            }
+        }
+
+        if progress {
+            self.handle_packets()?;
+        }
+
+        if lost {
+            self.lost();
+
+            return Ok(true);
+        }
+
+        Ok(progress)
+    }
+
+    // Drop the broken connection, the session decides whether to reconnect.
+    fn lost(&mut self) {
+        self.conn = Conn::Waiting;
+        self.read_buf.clear();
+        self.write_buf.clear();
+        self.session.connection_lost(Instant::now());
+    }
+
+    fn handle_packets(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let mut offset = 0;
+
+        while offset < self.read_buf.len() {
+            match read_packet(&self.read_buf[offset..]) {
+                Ok((remaining, packet)) => {
+                    offset = self.read_buf.len() - remaining.len();
+
+                    self.session.handle_packet(&packet, now)?;
+                }
+                Err(IError::Incomplete(_)) => break,
+                Err(err) => {
+                    warn!("fail to decode packet, {:?}", err);
+
+                    bail!(ErrorKind::InvalidPacket)
+                }
+            }
+        }
+
+        self.read_buf.drain(..offset);
+
+        Ok(())
+    }
+
+    fn poll_timer(&mut self, cx: &mut Context<'_>) -> Result<bool> {
+        let expired = match self.timer {
+            Some(ref mut timer) => timer.as_mut().poll(cx).is_ready(),
+            None => false,
+        };
+
+        if !expired {
+            return Ok(false);
+        }
+
+        let now = Instant::now();
+
+        self.session.handle_tick(now)?;
+
+        let next = match self.session.next_tick() {
+            Some(tick) => cmp::min(tick, now + TICK_INTERVAL),
+            None => now + TICK_INTERVAL,
+        };
+
+        if let Some(ref mut timer) = self.timer {
+            timer.as_mut().reset(time::Instant::from_std(cmp::max(next, now)));
+        }
+
+        Ok(true)
+    }
+
+    fn poll_write(&mut self, cx: &mut Context<'_>) -> Result<bool> {
+        let mut progress = false;
+        let mut lost = false;
+
+        if let Conn::Connected(ref mut stream) = self.conn {
+            while let Some(packet) = self.session.poll_packet() {
+                self.write_buf.write_packet(&packet.packet())?;
+            }
+
+            while !self.write_buf.is_empty() {
+                match Pin::new(&mut *stream).poll_write(cx, &self.write_buf) {
+                    Poll::Ready(Ok(0)) => {
+                        lost = true;
+                        break;
+                    }
+                    Poll::Ready(Ok(n)) => {
+                        self.write_buf.drain(..n);
+                        self.session.handle_tick(Instant::now())?;
+
+                        progress = true;
+                    }
+                    Poll::Ready(Err(err)) => {
+                        warn!("fail to write to the server, {}", err);
+
+                        lost = true;
+                        break;
+                    }
+                    Poll::Pending => break,
+                }
+            }
+        }
+
+        if lost {
+            self.lost();
+
+            return Ok(true);
+        }
+
+        Ok(progress)
+    }
+
+    fn dispatch(&mut self) -> Result<()> {
+        while let Some(event) = self.session.poll_event() {
+            match event {
+                Event::Connected { session_present } => {
+                    debug!("session {}", if session_present { "resumed" } else { "created" });
+
+                    if let Some(tx) = self.connected.take() {
+                        let _ = tx.send(Ok(()));
+                    }
+                }
+                Event::ConnectRefused(code) => bail!(ErrorKind::ConnectionRefused(code)),
+                Event::Message(msg) => {
+                    let _ = self.messages.send(msg);
+                }
+                Event::Published(packet_id) => {
+                    if let Some(Pending::Publish(tx)) = self.pending.remove(&packet_id) {
+                        let _ = tx.send(Ok(()));
+                    }
+                }
+                Event::Subscribed { packet_id, status } => {
+                    if let Some(Pending::Subscribe(tx)) = self.pending.remove(&packet_id) {
+                        let _ = tx.send(Ok(status.into_iter().map(|(_, code)| code).collect()));
+                    }
+                }
+                Event::Unsubscribed { packet_id, .. } => {
+                    if let Some(Pending::Unsubscribe(tx)) = self.pending.remove(&packet_id) {
+                        let _ = tx.send(Ok(()));
+                    }
+                }
+                Event::Timeout(packet_id) => {
+                    if let Some(pending) = self.pending.remove(&packet_id) {
+                        pending.fail(ErrorKind::Timeout.into());
+                    }
+                }
+                Event::PublishFailed(packet_id) => {
+                    if let Some(pending) = self.pending.remove(&packet_id) {
+                        pending.fail(ErrorKind::DeliveryFailed.into());
+                    }
+                }
+                Event::Pong { latency } => debug!("ping latency {:?}", latency),
+                Event::Reconnect { attempt } => {
+                    debug!("reconnecting, attempt {}", attempt);
+
+                    self.session.reconnect()?;
+                    self.conn = Conn::Connecting((self.open)());
+                }
+                Event::ReconnectFailed { .. } => bail!(ErrorKind::ConnectionClosed),
+                Event::Disconnected(DisconnectReason::Requested) => {}
+                Event::Disconnected(_) if self.session.is_reconnecting() => {
+                    if let Conn::Connected(_) = self.conn {
+                        self.conn = Conn::Waiting;
+                        self.read_buf.clear();
+                        self.write_buf.clear();
+                    }
+                }
+                Event::Disconnected(DisconnectReason::KeepAliveTimeout) => {
+                    bail!(ErrorKind::Timeout)
+                }
+                Event::Disconnected(_) => bail!(ErrorKind::ConnectionClosed),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Fails every outstanding request, the connection waiter receives the cause.
+    fn close(&mut self, err: Error) {
+        self.conn = Conn::Closed;
+
+        match self.connected.take() {
+            Some(tx) => {
+                let _ = tx.send(Err(err));
+            }
+            None => debug!("connection closed, {}", err),
+        }
+
+        for (_, pending) in mem::take(&mut self.pending) {
+            pending.fail(ErrorKind::ConnectionClosed.into());
+        }
+
+        for (_, tx) in self.blocked.drain(..) {
+            let _ = tx.send(Err(ErrorKind::ConnectionClosed.into()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+
+    use tokio::runtime::{Builder, Runtime};
+
+    use client::tests::echo_broker;
+    use super::*;
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().enable_all().build().unwrap()
+    }
+
+    #[test]
+    fn test_async_client() {
+        let _ = env_logger::init();
+
+        let rt = runtime();
+        let (addr, broker) = echo_broker(Arc::new(AtomicUsize::new(0)));
+        let (client, mut messages) = rt.block_on(AsyncClient::connect(
+            addr,
+            ConnectOptions {
+                keep_alive: 30,
+                ..Default::default()
+            },
+        )).unwrap();
+
+        assert_eq!(
+            rt.block_on(client.subscribe(&[("echo/#", QoS::AtLeastOnce)]))
+                .unwrap(),
+            vec![SubscribeReturnCode::Success(QoS::AtLeastOnce)]
+        );
+
+        for &qos in &[QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce] {
+            rt.block_on(client.clone().publish(Message::new("echo/test", "hello", qos)))
+                .unwrap();
+
+            assert_eq!(
+                rt.block_on(messages.recv()),
+                Some(Message::new("echo/test", "hello", QoS::AtMostOnce))
+            );
+        }
+
+        rt.block_on(client.disconnect()).unwrap();
+
+        // the stream ends and later requests fail once the event loop has stopped
+        assert_eq!(rt.block_on(messages.recv()), None);
+
+        match rt.block_on(client.publish(Message::new("echo/test", "bye", QoS::AtLeastOnce))) {
+            Err(Error(ErrorKind::ConnectionClosed, _)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn test_async_in_flight_window() {
+        let rt = runtime();
+        let (addr, broker) = echo_broker(Arc::new(AtomicUsize::new(0)));
+        let (client, _messages) = rt.block_on(AsyncClient::connect(
+            addr,
+            ConnectOptions {
+                max_in_flight: Some(1),
+                ..Default::default()
+            },
+        )).unwrap();
+
+        // the later messages wait for a free slot instead of failing
+        let replies = (0..3)
+            .map(|_| client.publish(Message::new("test", "hello", QoS::AtLeastOnce)))
+            .collect::<Vec<_>>();
+
+        for reply in replies {
+            rt.block_on(reply).unwrap();
+        }
+
+        rt.block_on(client.disconnect()).unwrap();
+
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn test_async_connect_url() {
+        let rt = runtime();
+        let (addr, broker) = echo_broker(Arc::new(AtomicUsize::new(0)));
+        let (client, _messages) = rt.block_on(
+            AsyncClient::connect_url(&format!("mqtt://{}/?keep_alive=30", addr)).unwrap(),
+        ).unwrap();
+
+        rt.block_on(client.publish(Message::new("echo/test", "hello", QoS::AtLeastOnce)))
+            .unwrap();
+        rt.block_on(client.disconnect()).unwrap();
+
+        broker.join().unwrap();
+
+        match AsyncClient::connect_url("wss://broker/mqtt") {
+            Err(Error(ErrorKind::InvalidUrl(ref reason), _)) => {
+                assert_eq!(reason, "unsupported endpoint wss://")
+            }
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_async_connect_failed() {
+        let rt = runtime();
+        let addr = {
+            let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+            listener.local_addr().unwrap()
+        };
+
+        assert!(
+            rt.block_on(AsyncClient::connect(addr, ConnectOptions::default()))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,644 @@
+//! A blocking MQTT client over `std::net::TcpStream`.
+//!
+//! ```no_run
+//! use mqtt::{Message, QoS};
+//! use mqtt::client::ConnectOptions;
+//! use mqtt::client::blocking::Client;
+//!
+//! let mut client = Client::connect("localhost:1883", ConnectOptions::default()).unwrap();
+//!
+//! client.subscribe(&[("sensor/+/temp", QoS::AtLeastOnce)]).unwrap();
+//! client.publish(Message::new("sensor/1/temp", "21.5", QoS::AtLeastOnce)).unwrap();
+//!
+//! let msg = client.recv().unwrap();
+//!
+//! client.disconnect().unwrap();
+//! ```
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, VecDeque};
+
+use nom::IError;
+
+use error::*;
+use proto::*;
+use packet::*;
+use decode::read_packet;
+use encode::WritePacketExt;
+use client::{Builder, ConnectOptions, DisconnectReason, Event, Session, State};
+
+const READ_BUF_SIZE: usize = 8 * 1024;
+
+/// A blocking client which sends acknowledgments and keep-alive pings on its own
+/// while waiting for the server, and reconnects following `ConnectOptions::reconnect`.
+pub struct Client {
+    addrs: Vec<SocketAddr>,
+    stream: TcpStream,
+    session: Session,
+    buf: Vec<u8>,
+    messages: VecDeque<Message>,
+    // the latest CONNACK, accepted or refused
+    connack: Option<Event>,
+    // the requests waited for, with their acknowledgment once received
+    acks: HashMap<PacketId, Option<Event>>,
+}
+
+impl Client {
+    /// Connect to the server and wait for it to accept the session.
+    pub fn connect<A: ToSocketAddrs>(addr: A, opts: ConnectOptions) -> Result<Client> {
+        Client::resume(addr, Session::new(), opts)
+    }
+
+    /// Connect to the server, resuming a session usually restored with `Session::with_store`.
+    pub fn resume<A: ToSocketAddrs>(
+        addr: A,
+        session: Session,
+        opts: ConnectOptions,
+    ) -> Result<Client> {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+
+        Client::start(open(&addrs)?, addrs, session, opts)
+    }
+
+    /// Connect to the server named by a connection URL, see `Builder::from_url`.
+    pub fn connect_url(url: &str) -> Result<Client> {
+        Client::with_builder(Builder::from_url(url)?)
+    }
+
+    /// Connect to the endpoint of the builder, with its session and options.
+    ///
+    /// Only `mqtt` endpoints are supported, the others fail with `InvalidUrl`.
+    pub fn with_builder(builder: Builder) -> Result<Client> {
+        let (addr, session, opts) = builder.into_tcp()?;
+
+        Client::resume((addr.0.as_str(), addr.1), session, opts)
+    }
+
+    /// Start a session over an already connected stream, reconnecting to its peer address.
+    pub fn with_stream(stream: TcpStream, opts: ConnectOptions) -> Result<Client> {
+        let addrs = vec![stream.peer_addr()?];
+
+        Client::start(stream, addrs, Session::new(), opts)
+    }
+
+    fn start(
+        stream: TcpStream,
+        addrs: Vec<SocketAddr>,
+        session: Session,
+        opts: ConnectOptions,
+    ) -> Result<Client> {
+        let mut client = Client {
+            addrs,
+            stream,
+            session,
+            buf: Vec::with_capacity(READ_BUF_SIZE),
+            messages: VecDeque::new(),
+            connack: None,
+            acks: HashMap::new(),
+        };
+
+        client.session.connect(opts)?;
+        client.flush()?;
+
+        loop {
+            match client.connack.take() {
+                Some(Event::ConnectRefused(code)) => bail!(ErrorKind::ConnectionRefused(code)),
+                Some(_) => return Ok(client),
+                None => {
+                    client.poll(None)?;
+                }
+            }
+        }
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Publish a message, waiting for it to be acknowledged when its QoS is 1 or 2.
+    ///
+    /// While the in-flight window is full, it first waits for a free slot.
+    pub fn publish(&mut self, msg: Message) -> Result<()> {
+        // wait for a free slot while the in-flight window is full
+        let packet_id = loop {
+            match self.session.publish(msg.clone()) {
+                Err(Error(ErrorKind::WouldBlock, _)) => self.poll(None)?,
+                res => break res?,
+            }
+        };
+
+        self.flush()?;
+
+        if let Some(packet_id) = packet_id {
+            self.wait_ack(packet_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Subscribe to the topic filters, returning the status granted to each of them.
+    pub fn subscribe(&mut self, topic_filters: &[(&str, QoS)]) -> Result<Vec<SubscribeReturnCode>> {
+        let packet_id = self.session.subscribe(topic_filters)?;
+
+        self.flush()?;
+
+        match self.wait_ack(packet_id)? {
+            Event::Subscribed { status, .. } => {
+                Ok(status.into_iter().map(|(_, code)| code).collect())
+            }
+            _ => bail!(ErrorKind::InvalidState),
+        }
+    }
+
+    pub fn unsubscribe(&mut self, topic_filters: &[&str]) -> Result<()> {
+        let packet_id = self.session.unsubscribe(topic_filters)?;
+
+        self.flush()?;
+        self.wait_ack(packet_id)?;
+
+        Ok(())
+    }
+
+    /// Wait for the next message from the server.
+    pub fn recv(&mut self) -> Result<Message> {
+        loop {
+            if let Some(msg) = self.messages.pop_front() {
+                return Ok(msg);
+            }
+
+            self.poll(None)?;
+        }
+    }
+
+    /// Wait for the next message from the server, returns `None` if none arrives in time.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Message>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if let Some(msg) = self.messages.pop_front() {
+                return Ok(Some(msg));
+            }
+
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+
+            self.poll(Some(deadline))?;
+        }
+    }
+
+    /// Send DISCONNECT and close the connection.
+    pub fn disconnect(mut self) -> Result<()> {
+        self.session.disconnect();
+        self.flush()?;
+        self.stream.shutdown(::std::net::Shutdown::Both)?;
+
+        Ok(())
+    }
+
+    // Wait for the acknowledgment of `packet_id`, only the acks waited for are kept.
+    fn wait_ack(&mut self, packet_id: PacketId) -> Result<Event> {
+        self.acks.insert(packet_id, None);
+
+        let res = loop {
+            match self.acks.get_mut(&packet_id).and_then(Option::take) {
+                Some(Event::Timeout(_)) => break Err(ErrorKind::Timeout.into()),
+                Some(Event::PublishFailed(_)) => break Err(ErrorKind::DeliveryFailed.into()),
+                Some(event) => break Ok(event),
+                None => {
+                    if let Err(err) = self.poll(None) {
+                        break Err(err);
+                    }
+                }
+            }
+        };
+
+        self.acks.remove(&packet_id);
+
+        res
+    }
+
+    // Read from the server until `deadline` or the next tick of the session clock,
+    // feeding every complete packet to the session.
+    fn poll(&mut self, deadline: Option<Instant>) -> Result<()> {
+        let now = Instant::now();
+        let wakeup = match (deadline, self.session.next_tick()) {
+            (Some(deadline), Some(tick)) => Some(cmp::min(deadline, tick)),
+            (deadline, tick) => deadline.or(tick),
+        };
+
+        if self.session.state() == State::Disconnected {
+            // wait for the next reconnect attempt
+            match wakeup {
+                Some(wakeup) => thread::sleep(wakeup.saturating_duration_since(now)),
+                None => bail!(ErrorKind::ConnectionClosed),
+            }
+        } else {
+            let timeout = wakeup.map(|wakeup| {
+                cmp::max(wakeup.saturating_duration_since(now), Duration::from_millis(1))
+            });
+
+            self.stream.set_read_timeout(timeout)?;
+
+            let mut buf = [0; READ_BUF_SIZE];
+
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    debug!("connection closed by the server");
+
+                    self.session.connection_lost(Instant::now());
+                }
+                Ok(n) => {
+                    self.buf.extend_from_slice(&buf[..n]);
+                    self.handle_packets()?;
+                }
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::WouldBlock ||
+                           err.kind() == io::ErrorKind::TimedOut => {}
+                Err(err) => {
+                    warn!("fail to read from the server, {}", err);
+
+                    self.session.connection_lost(Instant::now());
+                }
+            }
+        }
+
+        self.session.handle_tick(Instant::now())?;
+        self.flush()
+    }
+
+    fn handle_packets(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let mut offset = 0;
+
+        while offset < self.buf.len() {
+            match read_packet(&self.buf[offset..]) {
+                Ok((remaining, packet)) => {
+                    offset = self.buf.len() - remaining.len();
+
+                    self.session.handle_packet(&packet, now)?;
+                }
+                Err(IError::Incomplete(_)) => break,
+                Err(err) => {
+                    warn!("fail to decode packet, {:?}", err);
+
+                    bail!(ErrorKind::InvalidPacket)
+                }
+            }
+        }
+
+        self.buf.drain(..offset);
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        loop {
+            self.write()?;
+
+            let mut reconnect = false;
+
+            while let Some(event) = self.session.poll_event() {
+                match event {
+                    Event::Message(msg) => self.messages.push_back(msg),
+                    Event::Pong { .. } => {}
+                    Event::Reconnect { .. } => reconnect = true,
+                    Event::ReconnectFailed { .. } => bail!(ErrorKind::ConnectionClosed),
+                    Event::Disconnected(DisconnectReason::Requested) => {}
+                    Event::Disconnected(_) if self.session.is_reconnecting() => {}
+                    Event::Disconnected(DisconnectReason::KeepAliveTimeout) => {
+                        bail!(ErrorKind::Timeout)
+                    }
+                    Event::Disconnected(_) => bail!(ErrorKind::ConnectionClosed),
+                    Event::Connected { .. } | Event::ConnectRefused(_) => {
+                        self.connack = Some(event)
+                    }
+                    event => {
+                        match ack_id(&event).and_then(|id| self.acks.get_mut(&id)) {
+                            Some(ack) => *ack = Some(event),
+                            None => debug!("drop {:?}, nobody waits for it", event),
+                        }
+                    }
+                }
+            }
+
+            if !reconnect {
+                return Ok(());
+            }
+
+            self.reconnect();
+        }
+    }
+
+    fn write(&mut self) -> Result<()> {
+        loop {
+            let mut buf = vec![];
+
+            while let Some(packet) = self.session.poll_packet() {
+                buf.write_packet(&packet.packet())?;
+            }
+
+            if buf.is_empty() {
+                return Ok(());
+            }
+
+            if let Err(err) = self.stream.write_all(&buf) {
+                warn!("fail to write to the server, {}", err);
+
+                self.session.connection_lost(Instant::now());
+
+                return Ok(());
+            }
+
+            self.session.handle_tick(Instant::now())?;
+        }
+    }
+
+    fn reconnect(&mut self) {
+        self.buf.clear();
+
+        let res = self.session.reconnect().and_then(|_| open(&self.addrs));
+
+        match res {
+            Ok(stream) => self.stream = stream,
+            Err(err) => {
+                warn!("fail to reconnect, {}", err);
+
+                self.session.connection_lost(Instant::now());
+            }
+        }
+    }
+}
+
+// The packet id of the request acknowledged by `event`.
+fn ack_id(event: &Event) -> Option<PacketId> {
+    match *event {
+        Event::Published(id) |
+        Event::Timeout(id) |
+        Event::PublishFailed(id) |
+        Event::Subscribed { packet_id: id, .. } |
+        Event::Unsubscribed { packet_id: id, .. } => Some(id),
+        _ => None,
+    }
+}
+
+fn open(addrs: &[SocketAddr]) -> Result<TcpStream> {
+    let stream = TcpStream::connect(addrs)?;
+
+    stream.set_nodelay(true)?;
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use std::thread;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use client::ReconnectPolicy;
+    use client::tests::echo_broker;
+    use super::*;
+
+    // Read from `stream` until `n` packets have been passed to `f`.
+    fn expect_packets<F: FnMut(&Packet)>(stream: &mut TcpStream, n: usize, mut f: F) {
+        let mut buf = vec![];
+        let mut seen = 0;
+
+        while seen < n {
+            let mut data = [0; READ_BUF_SIZE];
+            let len = stream.read(&mut data).unwrap();
+
+            assert!(len > 0, "connection closed by the client");
+
+            buf.extend_from_slice(&data[..len]);
+
+            let mut offset = 0;
+
+            while let Ok((remaining, packet)) = read_packet(&buf[offset..]) {
+                offset = buf.len() - remaining.len();
+                seen += 1;
+
+                f(&packet);
+            }
+
+            buf.drain(..offset);
+        }
+    }
+
+    fn reply(stream: &mut TcpStream, packet: &Packet) {
+        let mut buf = vec![];
+
+        buf.write_packet(packet).unwrap();
+        stream.write_all(&buf).unwrap();
+    }
+
+    #[test]
+    fn test_blocking_client() {
+        let _ = env_logger::init();
+
+        let (addr, broker) = echo_broker(Arc::new(AtomicUsize::new(0)));
+        let mut client = Client::connect(addr.as_str(), ConnectOptions::default()).unwrap();
+
+        assert_eq!(
+            client
+                .subscribe(&[("echo/#", QoS::ExactlyOnce), ("other", QoS::AtMostOnce)])
+                .unwrap(),
+            vec![
+                SubscribeReturnCode::Success(QoS::ExactlyOnce),
+                SubscribeReturnCode::Success(QoS::AtMostOnce),
+            ]
+        );
+
+        for &qos in &[QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce] {
+            client.publish(Message::new("echo/test", "hello", qos)).unwrap();
+
+            assert_eq!(
+                client.recv().unwrap(),
+                Message::new("echo/test", "hello", QoS::AtMostOnce)
+            );
+        }
+
+        assert_eq!(client.session().in_flight(), 0);
+        assert_eq!(
+            client.recv_timeout(Duration::from_millis(50)).unwrap(),
+            None
+        );
+
+        client.disconnect().unwrap();
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn test_blocking_keep_alive() {
+        let pings = Arc::new(AtomicUsize::new(0));
+        let (addr, broker) = echo_broker(pings.clone());
+        let mut client = Client::connect(
+            addr.as_str(),
+            ConnectOptions {
+                keep_alive: 1,
+                ..Default::default()
+            },
+        ).unwrap();
+
+        assert_eq!(
+            client.recv_timeout(Duration::from_millis(1500)).unwrap(),
+            None
+        );
+        assert_eq!(pings.load(Ordering::SeqCst), 1);
+
+        client.disconnect().unwrap();
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn test_blocking_connect_url() {
+        let (addr, broker) = echo_broker(Arc::new(AtomicUsize::new(0)));
+        let mut client = Client::connect_url(&format!("mqtt://{}/?client_id=url", addr)).unwrap();
+
+        client.publish(Message::new("echo/test", "hello", QoS::AtLeastOnce)).unwrap();
+
+        assert_eq!(client.session().in_flight(), 0);
+
+        client.disconnect().unwrap();
+        broker.join().unwrap();
+
+        let unsupported = [
+            "mqtts://broker",
+            "ws://broker/mqtt",
+            "wss://broker/mqtt",
+            "unix:///tmp/mqtt",
+        ];
+
+        for url in &unsupported {
+            match Client::connect_url(url) {
+                Err(Error(ErrorKind::InvalidUrl(ref reason), _)) => {
+                    assert!(reason.starts_with("unsupported endpoint"), "{}", reason)
+                }
+                res => panic!("unexpected result {:?}", res.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_blocking_connect_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = vec![];
+
+            buf.write_packet(&Packet::ConnectAck {
+                session_present: false,
+                return_code: ConnectReturnCode::NotAuthorized,
+            }).unwrap();
+
+            stream.write_all(&buf).unwrap();
+        });
+
+        match Client::connect(addr, ConnectOptions::default()) {
+            Err(Error(ErrorKind::ConnectionRefused(ConnectReturnCode::NotAuthorized), _)) => {}
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn test_blocking_reconnect() {
+        let _ = env_logger::init();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let broker = thread::spawn(move || {
+            let connack = Packet::ConnectAck {
+                session_present: false,
+                return_code: ConnectReturnCode::ConnectionAccepted,
+            };
+            let suback = |packet_id| {
+                Packet::SubscribeAck {
+                    packet_id,
+                    status: vec![SubscribeReturnCode::Success(QoS::AtLeastOnce)],
+                }
+            };
+
+            // the first connection is dropped before the message is acknowledged
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut subscribe = 0;
+
+            expect_packets(&mut stream, 1, |packet| assert_eq!(packet.packet_type(), CONNECT));
+            reply(&mut stream, &connack);
+            expect_packets(&mut stream, 1, |packet| match *packet {
+                Packet::Subscribe { packet_id, .. } => subscribe = packet_id,
+                ref packet => panic!("unexpected packet {:?}", packet),
+            });
+            reply(&mut stream, &suback(subscribe));
+            expect_packets(&mut stream, 1, |packet| assert_eq!(packet.packet_type(), PUBLISH));
+
+            drop(stream);
+
+            // the new session gets the message again and the subscription restored
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut acks = vec![];
+
+            expect_packets(&mut stream, 1, |packet| assert_eq!(packet.packet_type(), CONNECT));
+            reply(&mut stream, &connack);
+            expect_packets(&mut stream, 2, |packet| match *packet {
+                Packet::Publish { packet_id: Some(packet_id), .. } => {
+                    acks.push(Packet::PublishAck { packet_id })
+                }
+                Packet::Subscribe {
+                    packet_id,
+                    ref topic_filters,
+                } => {
+                    assert_eq!(topic_filters, &vec![("echo/#", QoS::AtLeastOnce)]);
+
+                    acks.push(suback(packet_id))
+                }
+                ref packet => panic!("unexpected packet {:?}", packet),
+            });
+
+            for ack in &acks {
+                reply(&mut stream, ack);
+            }
+
+            expect_packets(&mut stream, 1, |packet| assert_eq!(packet.packet_type(), DISCONNECT));
+        });
+
+        let mut client = Client::connect(
+            addr,
+            ConnectOptions {
+                clean_session: false,
+                reconnect: Some(ReconnectPolicy {
+                    initial_delay: Duration::from_millis(10),
+                    jitter: 0.0,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        ).unwrap();
+
+        client.subscribe(&[("echo/#", QoS::AtLeastOnce)]).unwrap();
+        client
+            .publish(Message::new("echo/test", "hello", QoS::AtLeastOnce))
+            .unwrap();
+
+        assert_eq!(
+            client.session().subscriptions().get("echo/#"),
+            Some(&QoS::AtLeastOnce)
+        );
+        // the acks of the restored subscription, waited by nobody, are not kept
+        assert!(client.acks.is_empty());
+
+        client.disconnect().unwrap();
+        broker.join().unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! Tracking the delivery of published messages.
+//!
+//! `Client::publish` returns a `DeliveryToken` for every message, which completes
+//! once the message has been delivered or abandoned. The token can be polled with
+//! `state`, or waited on from another thread with `wait` and `wait_timeout`.
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use error::*;
+use proto::*;
+
+/// The delivery state of a published message.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DeliveryState {
+    /// the message is waiting to be sent or acknowledged.
+    Pending,
+    /// the message has been sent (QoS 0) or completely acknowledged (QoS 1 and QoS 2).
+    Published,
+    /// the message could not be delivered.
+    Failed(DeliveryFailure),
+}
+
+/// Why a published message could not be delivered.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DeliveryFailure {
+    /// the message was not acknowledged before the ack timeout.
+    Timeout,
+    /// the message was resent too many times by the retry policy,
+    /// dropped from the full offline queue or by a clean session.
+    Abandoned,
+}
+
+impl DeliveryFailure {
+    pub fn reason(&self) -> &'static str {
+        match *self {
+            DeliveryFailure::Timeout => "acknowledgment timed out",
+            DeliveryFailure::Abandoned => "message abandoned",
+        }
+    }
+}
+
+impl fmt::Display for DeliveryFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.reason())
+    }
+}
+
+/// A handle on the delivery of one published message.
+///
+/// Tokens are cheap to clone, all the clones share the same state.
+#[derive(Debug, Clone)]
+pub struct DeliveryToken {
+    packet_id: Option<PacketId>,
+    state: Arc<(Mutex<DeliveryState>, Condvar)>,
+}
+
+impl DeliveryToken {
+    pub(crate) fn new(packet_id: Option<PacketId>) -> Self {
+        DeliveryToken {
+            packet_id,
+            state: Arc::new((Mutex::new(DeliveryState::Pending), Condvar::new())),
+        }
+    }
+
+    /// The packet id of a QoS 1 or QoS 2 message, `None` for QoS 0.
+    pub fn packet_id(&self) -> Option<PacketId> {
+        self.packet_id
+    }
+
+    pub fn state(&self) -> DeliveryState {
+        *self.state.0.lock().unwrap()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.state() != DeliveryState::Pending
+    }
+
+    /// Block until the message is delivered, fails with `DeliveryFailed` or `Timeout`.
+    pub fn wait(&self) -> Result<()> {
+        let (lock, cond) = &*self.state;
+        let mut state = lock.lock().unwrap();
+
+        while *state == DeliveryState::Pending {
+            state = cond.wait(state).unwrap();
+        }
+
+        result(*state)
+    }
+
+    /// Block until the message is delivered or `timeout` elapsed.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let (lock, cond) = &*self.state;
+        let mut state = lock.lock().unwrap();
+
+        while *state == DeliveryState::Pending {
+            let now = Instant::now();
+
+            if now >= deadline {
+                bail!(ErrorKind::Timeout)
+            }
+
+            state = cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+
+        result(*state)
+    }
+
+    /// Complete the token, waking up the waiting threads.
+    pub(crate) fn complete(&self, new_state: DeliveryState) {
+        let (lock, cond) = &*self.state;
+        let mut state = lock.lock().unwrap();
+
+        if *state == DeliveryState::Pending {
+            *state = new_state;
+
+            cond.notify_all();
+        }
+    }
+}
+
+fn result(state: DeliveryState) -> Result<()> {
+    match state {
+        DeliveryState::Failed(DeliveryFailure::Timeout) => bail!(ErrorKind::Timeout),
+        DeliveryState::Failed(DeliveryFailure::Abandoned) => bail!(ErrorKind::DeliveryFailed),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_delivery_token() {
+        let token = DeliveryToken::new(Some(1));
+
+        assert_eq!(token.packet_id(), Some(1));
+        assert_eq!(token.state(), DeliveryState::Pending);
+        assert!(token.wait_timeout(Duration::from_millis(10)).is_err());
+
+        let waiter = {
+            let token = token.clone();
+
+            thread::spawn(move || token.wait().is_ok())
+        };
+
+        token.complete(DeliveryState::Published);
+
+        assert!(waiter.join().unwrap());
+        assert!(token.is_complete());
+
+        // a completed token never changes
+        token.complete(DeliveryState::Failed(DeliveryFailure::Timeout));
+
+        assert_eq!(token.state(), DeliveryState::Published);
+
+        let token = DeliveryToken::new(Some(2));
+
+        token.complete(DeliveryState::Failed(DeliveryFailure::Abandoned));
+
+        match token.wait() {
+            Err(Error(ErrorKind::DeliveryFailed, _)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,904 @@
+//! Persistent storage for the in-flight state of a client session.
+//!
+//! A `Session` created with `Session::with_store` writes every QoS 1 and QoS 2 message
+//! waiting for acknowledgment, every QoS 2 message received but not yet released and
+//! the next packet id through a `SessionStore`, so that a restarted process can resume
+//! a session opened with `clean_session = false`.
+//!
+//! ```no_run
+//! use mqtt::client::{ConnectOptions, Session};
+//! use mqtt::client::store::FileStore;
+//!
+//! let store = FileStore::open("/var/lib/gateway/session.log").unwrap();
+//! let mut session = Session::with_store(store).unwrap();
+//!
+//! session.connect(ConnectOptions {
+//!     clean_session: false,
+//!     ..Default::default()
+//! }).unwrap();
+//! ```
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+
+use error::*;
+use proto::*;
+use encode::MAX_VARIABLE_LENGTH;
+use snapshot::{crc32, SnapshotValue};
+use client::Outgoing;
+
+/// Storage for the state a session must not lose across restarts.
+///
+/// Only PUBLISH and PUBREL packets are stored as outgoing, subscriptions in flight
+/// are forgotten with the process.
+pub trait SessionStore: fmt::Debug {
+    /// Save an outgoing packet waiting for acknowledgment, replacing any with the same id.
+    fn put_outgoing(&mut self, packet_id: PacketId, packet: &Outgoing) -> Result<()>;
+
+    fn remove_outgoing(&mut self, packet_id: PacketId) -> Result<()>;
+
+    /// Returns the outgoing packets ordered by packet id.
+    fn outgoing(&self) -> Result<Vec<(PacketId, Outgoing)>>;
+
+    /// Save the id of a QoS 2 message received but not released yet,
+    /// with the message itself if its delivery waits for PUBREL.
+    fn put_received(&mut self, packet_id: PacketId, msg: Option<&Message>) -> Result<()>;
+
+    fn remove_received(&mut self, packet_id: PacketId) -> Result<()>;
+
+    /// Returns the received QoS 2 messages ordered by packet id.
+    fn received(&self) -> Result<Vec<(PacketId, Option<Message>)>>;
+
+    fn set_next_packet_id(&mut self, packet_id: PacketId) -> Result<()>;
+
+    /// Returns the next packet id to allocate, `None` if it was never saved.
+    fn next_packet_id(&self) -> Result<Option<PacketId>>;
+
+    /// Forget everything, when a clean session starts.
+    fn clear(&mut self) -> Result<()>;
+}
+
+/// A store keeping the session state in memory.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MemoryStore {
+    outgoing: BTreeMap<PacketId, Outgoing>,
+    received: BTreeMap<PacketId, Option<Message>>,
+    next_packet_id: Option<PacketId>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn len(&self) -> usize {
+        self.outgoing.len() + self.received.len()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn put_outgoing(&mut self, packet_id: PacketId, packet: &Outgoing) -> Result<()> {
+        self.outgoing.insert(packet_id, packet.clone());
+
+        Ok(())
+    }
+
+    fn remove_outgoing(&mut self, packet_id: PacketId) -> Result<()> {
+        self.outgoing.remove(&packet_id);
+
+        Ok(())
+    }
+
+    fn outgoing(&self) -> Result<Vec<(PacketId, Outgoing)>> {
+        Ok(self.outgoing
+            .iter()
+            .map(|(&packet_id, packet)| (packet_id, packet.clone()))
+            .collect())
+    }
+
+    fn put_received(&mut self, packet_id: PacketId, msg: Option<&Message>) -> Result<()> {
+        self.received.insert(packet_id, msg.cloned());
+
+        Ok(())
+    }
+
+    fn remove_received(&mut self, packet_id: PacketId) -> Result<()> {
+        self.received.remove(&packet_id);
+
+        Ok(())
+    }
+
+    fn received(&self) -> Result<Vec<(PacketId, Option<Message>)>> {
+        Ok(self.received
+            .iter()
+            .map(|(&packet_id, msg)| (packet_id, msg.clone()))
+            .collect())
+    }
+
+    fn set_next_packet_id(&mut self, packet_id: PacketId) -> Result<()> {
+        self.next_packet_id = Some(packet_id);
+
+        Ok(())
+    }
+
+    fn next_packet_id(&self) -> Result<Option<PacketId>> {
+        Ok(self.next_packet_id)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        *self = MemoryStore::default();
+
+        Ok(())
+    }
+}
+
+pub const LOG_MAGIC: &[u8; 4] = b"MQSL";
+pub const LOG_VERSION: u8 = 1;
+
+// rewrite the log once it holds this many records more than the live entries
+const COMPACT_THRESHOLD: usize = 1024;
+
+const RECORD_PUT_OUTGOING: u8 = 1;
+const RECORD_REMOVE_OUTGOING: u8 = 2;
+const RECORD_PUT_RECEIVED: u8 = 3;
+const RECORD_REMOVE_RECEIVED: u8 = 4;
+const RECORD_NEXT_PACKET_ID: u8 = 5;
+const RECORD_CLEAR: u8 = 6;
+
+const PACKET_PUBLISH: u8 = 1;
+const PACKET_PUBLISH_RELEASE: u8 = 2;
+
+/// A store appending every change to a log file, which is compacted once it grows
+/// well beyond the live state.
+///
+/// The log starts with the magic `MQSL` and a version byte, each record is prefixed
+/// with its length and followed by its CRC-32 checksum. A torn record at the end of
+/// the log, left by a crash in the middle of a write, is discarded when it is opened,
+/// while a bad record followed by others fails with `CorruptStore`.
+///
+/// Every record is synced to the disk before the change returns.
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    file: BufWriter<File>,
+    state: MemoryStore,
+    records: usize,
+}
+
+impl FileStore {
+    /// Open the log at `path`, creating it if it doesn't exist, and replay its records.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileStore> {
+        let path = path.as_ref().to_owned();
+        let mut state = MemoryStore::new();
+
+        match File::open(&path) {
+            Ok(mut file) => {
+                let mut buf = vec![];
+
+                file.read_to_end(&mut buf)?;
+
+                replay(&buf, &mut state)?;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => bail!(err),
+        }
+
+        let file = compact(&path, &state)?;
+
+        Ok(FileStore {
+            path,
+            file,
+            records: state.len(),
+            state,
+        })
+    }
+
+    /// Rewrite the log with only the live state.
+    pub fn compact(&mut self) -> Result<()> {
+        self.file = compact(&self.path, &self.state)?;
+        self.records = self.state.len();
+
+        Ok(())
+    }
+
+    fn append(&mut self, record: &[u8]) -> Result<()> {
+        write_record(&mut self.file, record)?;
+
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.records += 1;
+
+        if self.records > self.state.len() + COMPACT_THRESHOLD {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl SessionStore for FileStore {
+    fn put_outgoing(&mut self, packet_id: PacketId, packet: &Outgoing) -> Result<()> {
+        let mut record = vec![RECORD_PUT_OUTGOING];
+
+        packet_id.write_value(&mut record)?;
+        write_outgoing(&mut record, packet)?;
+
+        self.state.put_outgoing(packet_id, packet)?;
+        self.append(&record)
+    }
+
+    fn remove_outgoing(&mut self, packet_id: PacketId) -> Result<()> {
+        let mut record = vec![RECORD_REMOVE_OUTGOING];
+
+        packet_id.write_value(&mut record)?;
+
+        self.state.remove_outgoing(packet_id)?;
+        self.append(&record)
+    }
+
+    fn outgoing(&self) -> Result<Vec<(PacketId, Outgoing)>> {
+        self.state.outgoing()
+    }
+
+    fn put_received(&mut self, packet_id: PacketId, msg: Option<&Message>) -> Result<()> {
+        let mut record = vec![RECORD_PUT_RECEIVED];
+
+        packet_id.write_value(&mut record)?;
+        write_message(&mut record, msg)?;
+
+        self.state.put_received(packet_id, msg)?;
+        self.append(&record)
+    }
+
+    fn remove_received(&mut self, packet_id: PacketId) -> Result<()> {
+        let mut record = vec![RECORD_REMOVE_RECEIVED];
+
+        packet_id.write_value(&mut record)?;
+
+        self.state.remove_received(packet_id)?;
+        self.append(&record)
+    }
+
+    fn received(&self) -> Result<Vec<(PacketId, Option<Message>)>> {
+        self.state.received()
+    }
+
+    fn set_next_packet_id(&mut self, packet_id: PacketId) -> Result<()> {
+        let mut record = vec![RECORD_NEXT_PACKET_ID];
+
+        packet_id.write_value(&mut record)?;
+
+        self.state.set_next_packet_id(packet_id)?;
+        self.append(&record)
+    }
+
+    fn next_packet_id(&self) -> Result<Option<PacketId>> {
+        self.state.next_packet_id()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.state.clear()?;
+        self.append(&[RECORD_CLEAR])
+    }
+}
+
+// Write the live state to a new log and atomically replace the old one with it.
+fn compact(path: &Path, state: &MemoryStore) -> Result<BufWriter<File>> {
+    let mut tmp = path.as_os_str().to_owned();
+
+    tmp.push(".tmp");
+
+    let tmp = PathBuf::from(tmp);
+
+    {
+        let mut file = BufWriter::new(File::create(&tmp)?);
+
+        file.write_all(LOG_MAGIC)?;
+        file.write_u8(LOG_VERSION)?;
+
+        if let Some(packet_id) = state.next_packet_id {
+            let mut record = vec![RECORD_NEXT_PACKET_ID];
+
+            packet_id.write_value(&mut record)?;
+            write_record(&mut file, &record)?;
+        }
+
+        for (&packet_id, packet) in &state.outgoing {
+            let mut record = vec![RECORD_PUT_OUTGOING];
+
+            packet_id.write_value(&mut record)?;
+            write_outgoing(&mut record, packet)?;
+            write_record(&mut file, &record)?;
+        }
+
+        for (&packet_id, msg) in &state.received {
+            let mut record = vec![RECORD_PUT_RECEIVED];
+
+            packet_id.write_value(&mut record)?;
+            write_message(&mut record, msg.as_ref())?;
+            write_record(&mut file, &record)?;
+        }
+
+        file.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    }
+
+    fs::rename(&tmp, path)?;
+
+    // make the rename itself durable
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+
+        File::open(dir)?.sync_all()?;
+    }
+
+    let file = OpenOptions::new().append(true).open(path)?;
+
+    Ok(BufWriter::new(file))
+}
+
+fn replay(buf: &[u8], state: &mut MemoryStore) -> Result<()> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+
+    if buf.len() < LOG_MAGIC.len() + 1 || &buf[..LOG_MAGIC.len()] != LOG_MAGIC {
+        bail!(ErrorKind::InvalidSnapshot)
+    }
+
+    if buf[LOG_MAGIC.len()] != LOG_VERSION {
+        warn!("unsupported session log version {}", buf[LOG_MAGIC.len()]);
+
+        bail!(ErrorKind::InvalidSnapshot)
+    }
+
+    let mut r = Cursor::new(&buf[LOG_MAGIC.len() + 1..]);
+
+    while let Some(record) = read_record(&mut r)? {
+        let mut r = Cursor::new(record);
+
+        match r.read_u8()? {
+            RECORD_PUT_OUTGOING => {
+                let packet_id = PacketId::read_value(&mut r)?;
+
+                state.put_outgoing(packet_id, &read_outgoing(&mut r)?)?;
+            }
+            RECORD_REMOVE_OUTGOING => state.remove_outgoing(PacketId::read_value(&mut r)?)?,
+            RECORD_PUT_RECEIVED => {
+                let packet_id = PacketId::read_value(&mut r)?;
+
+                state.put_received(packet_id, read_message(&mut r)?.as_ref())?;
+            }
+            RECORD_REMOVE_RECEIVED => state.remove_received(PacketId::read_value(&mut r)?)?,
+            RECORD_NEXT_PACKET_ID => state.set_next_packet_id(PacketId::read_value(&mut r)?)?,
+            RECORD_CLEAR => state.clear()?,
+            tag => {
+                warn!("unknown session log record {}", tag);
+
+                bail!(ErrorKind::InvalidSnapshot)
+            }
+        }
+    }
+
+    let remaining = r.get_ref().len() as u64 - r.position();
+
+    if remaining > 0 {
+        warn!("discard {} bytes of a torn record at the end of the session log", remaining);
+    }
+
+    Ok(())
+}
+
+fn write_record<W: Write>(w: &mut W, record: &[u8]) -> io::Result<()> {
+    w.write_u32::<BigEndian>(record.len() as u32)?;
+    w.write_all(record)?;
+    w.write_u32::<BigEndian>(crc32(record))
+}
+
+// Returns the next record, or `None` at the end of the log or on a torn last record.
+fn read_record<'a>(r: &mut Cursor<&'a [u8]>) -> Result<Option<&'a [u8]>> {
+    let start = r.position() as usize;
+    let buf = *r.get_ref();
+
+    if buf.len() < start + 4 {
+        return Ok(None);
+    }
+
+    let len = r.read_u32::<BigEndian>()? as usize;
+    let body = start + 4;
+    let end = body.saturating_add(len).saturating_add(4);
+
+    let corrupt = || ErrorKind::CorruptStore((LOG_MAGIC.len() + 1 + start) as u64);
+
+    if buf.len() < end {
+        // a torn write only leaves a prefix of the last record,
+        // a record longer than a packet or followed by another one has a corrupt length
+        if len > MAX_VARIABLE_LENGTH || has_record(&buf[body..]) {
+            warn!("bad length of the session log record at offset {}", start);
+
+            bail!(corrupt())
+        }
+
+        r.set_position(start as u64);
+
+        return Ok(None);
+    }
+
+    let record = &buf[body..body + len];
+    let checksum = BigEndian::read_u32(&buf[body + len..end]);
+
+    if crc32(record) != checksum {
+        r.set_position(start as u64);
+
+        if end == buf.len() {
+            return Ok(None);
+        }
+
+        warn!("bad checksum of the session log record at offset {}", start);
+
+        bail!(corrupt())
+    }
+
+    r.set_position(end as u64);
+
+    Ok(Some(record))
+}
+
+// Whether a complete record with a valid checksum starts anywhere in `buf`.
+fn has_record(buf: &[u8]) -> bool {
+    (0..buf.len().saturating_sub(8)).any(|pos| {
+        let len = BigEndian::read_u32(&buf[pos..]) as usize;
+        let body = pos + 4;
+
+        // records are never empty, which also skips the zeros of a torn write
+        len > 0 && body + len + 4 <= buf.len() &&
+            crc32(&buf[body..body + len]) == BigEndian::read_u32(&buf[body + len..])
+    })
+}
+
+fn write_outgoing<W: Write>(w: &mut W, packet: &Outgoing) -> io::Result<()> {
+    match *packet {
+        Outgoing::Publish {
+            dup,
+            retain,
+            packet_id,
+            ref msg,
+        } => {
+            w.write_u8(PACKET_PUBLISH)?;
+            dup.write_value(w)?;
+            retain.write_value(w)?;
+            packet_id.write_value(w)?;
+            write_message(w, Some(msg))
+        }
+        Outgoing::PublishRelease(packet_id) => {
+            w.write_u8(PACKET_PUBLISH_RELEASE)?;
+            packet_id.write_value(w)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only PUBLISH and PUBREL can be stored",
+        )),
+    }
+}
+
+fn read_outgoing<R: Read>(r: &mut R) -> Result<Outgoing> {
+    match r.read_u8()? {
+        PACKET_PUBLISH => {
+            let dup = bool::read_value(r)?;
+            let retain = bool::read_value(r)?;
+            let packet_id = Option::read_value(r)?;
+            let msg = read_message(r)?.ok_or(ErrorKind::InvalidSnapshot)?;
+
+            Ok(Outgoing::Publish {
+                dup,
+                retain,
+                packet_id,
+                msg,
+            })
+        }
+        PACKET_PUBLISH_RELEASE => Ok(Outgoing::PublishRelease(PacketId::read_value(r)?)),
+        _ => bail!(ErrorKind::InvalidSnapshot),
+    }
+}
+
+fn write_message<W: Write>(w: &mut W, msg: Option<&Message>) -> io::Result<()> {
+    match msg {
+        Some(msg) => {
+            w.write_u8(1)?;
+            msg.topic.write_value(w)?;
+            msg.payload.to_vec().write_value(w)?;
+            w.write_u8(msg.qos.into())
+        }
+        None => w.write_u8(0),
+    }
+}
+
+fn read_message<R: Read>(r: &mut R) -> Result<Option<Message>> {
+    if r.read_u8()? == 0 {
+        return Ok(None);
+    }
+
+    let topic = String::read_value(r)?;
+    let payload = Vec::read_value(r)?;
+    let qos = r.read_u8()?;
+
+    if qos > QoS::ExactlyOnce.into() {
+        bail!(ErrorKind::InvalidSnapshot)
+    }
+
+    Ok(Some(Message::new(topic, payload, QoS::from(qos))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use std::time::Instant;
+
+    use packet::*;
+    use client::{ConnectOptions, Event, Session};
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("mqtt-{}-{}.log", name, process::id()));
+
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    fn connect(session: &mut Session, session_present: bool) -> Vec<Outgoing> {
+        session.connect(ConnectOptions {
+            clean_session: false,
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(session.poll_packet().unwrap().packet().packet_type(), CONNECT);
+
+        session
+            .handle_packet(
+                &Packet::ConnectAck {
+                    session_present,
+                    return_code: ConnectReturnCode::ConnectionAccepted,
+                },
+                Instant::now(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            session.poll_event(),
+            Some(Event::Connected { session_present })
+        );
+
+        let mut packets = vec![];
+
+        while let Some(packet) = session.poll_packet() {
+            packets.push(packet);
+        }
+
+        packets
+    }
+
+    fn receive(session: &mut Session, packet_id: PacketId, dup: bool) {
+        session
+            .handle_packet(
+                &Packet::Publish {
+                    dup,
+                    retain: false,
+                    qos: QoS::ExactlyOnce,
+                    topic: "topic",
+                    packet_id: Some(packet_id),
+                    payload: b"data",
+                },
+                Instant::now(),
+            )
+            .unwrap()
+    }
+
+    // A store which can't save any message.
+    #[derive(Debug)]
+    struct FullStore(MemoryStore);
+
+    impl SessionStore for FullStore {
+        fn put_outgoing(&mut self, _: PacketId, _: &Outgoing) -> Result<()> {
+            bail!("disk full")
+        }
+
+        fn remove_outgoing(&mut self, packet_id: PacketId) -> Result<()> {
+            self.0.remove_outgoing(packet_id)
+        }
+
+        fn outgoing(&self) -> Result<Vec<(PacketId, Outgoing)>> {
+            self.0.outgoing()
+        }
+
+        fn put_received(&mut self, _: PacketId, _: Option<&Message>) -> Result<()> {
+            bail!("disk full")
+        }
+
+        fn remove_received(&mut self, packet_id: PacketId) -> Result<()> {
+            self.0.remove_received(packet_id)
+        }
+
+        fn received(&self) -> Result<Vec<(PacketId, Option<Message>)>> {
+            self.0.received()
+        }
+
+        fn set_next_packet_id(&mut self, packet_id: PacketId) -> Result<()> {
+            self.0.set_next_packet_id(packet_id)
+        }
+
+        fn next_packet_id(&self) -> Result<Option<PacketId>> {
+            self.0.next_packet_id()
+        }
+
+        fn clear(&mut self) -> Result<()> {
+            self.0.clear()
+        }
+    }
+
+    fn publish(payload: &'static str, qos: QoS, dup: bool) -> Outgoing {
+        Outgoing::Publish {
+            dup,
+            retain: true,
+            packet_id: Some(1),
+            msg: Message::new("topic", payload, qos),
+        }
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = temp_path("store");
+
+        {
+            let mut store = FileStore::open(&path).unwrap();
+
+            store.set_next_packet_id(4).unwrap();
+            store.put_outgoing(1, &publish("first", QoS::ExactlyOnce, false)).unwrap();
+            store.put_outgoing(2, &publish("second", QoS::ExactlyOnce, false)).unwrap();
+            store.put_outgoing(3, &publish("third", QoS::ExactlyOnce, false)).unwrap();
+            store.remove_outgoing(2).unwrap();
+            store.put_outgoing(3, &Outgoing::PublishRelease(3)).unwrap();
+            store.put_outgoing(4, &publish("fourth", QoS::AtLeastOnce, false)).unwrap();
+            store.put_outgoing(5, &publish("fifth", QoS::AtLeastOnce, true)).unwrap();
+            store.put_outgoing(6, &publish("sixth", QoS::ExactlyOnce, true)).unwrap();
+            store.put_received(7, None).unwrap();
+            store
+                .put_received(8, Some(&Message::new("topic", "held", QoS::ExactlyOnce)))
+                .unwrap();
+            store.remove_received(7).unwrap();
+        }
+
+        // a torn record left by a crash is discarded
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[0, 0, 0, 9, RECORD_CLEAR])
+            .unwrap();
+
+        let mut store = FileStore::open(&path).unwrap();
+
+        assert_eq!(store.next_packet_id().unwrap(), Some(4));
+        // both original and resent messages keep their DUP flag
+        assert_eq!(
+            store.outgoing().unwrap(),
+            vec![
+                (1, publish("first", QoS::ExactlyOnce, false)),
+                (3, Outgoing::PublishRelease(3)),
+                (4, publish("fourth", QoS::AtLeastOnce, false)),
+                (5, publish("fifth", QoS::AtLeastOnce, true)),
+                (6, publish("sixth", QoS::ExactlyOnce, true)),
+            ]
+        );
+        assert_eq!(
+            store.received().unwrap(),
+            vec![(8, Some(Message::new("topic", "held", QoS::ExactlyOnce)))]
+        );
+
+        // the log is compacted to the live state
+        let len = fs::metadata(&path).unwrap().len();
+
+        for _ in 0..2 * COMPACT_THRESHOLD {
+            store.put_outgoing(2, &publish("again", QoS::ExactlyOnce, false)).unwrap();
+        }
+
+        assert!(store.records <= store.state.len() + COMPACT_THRESHOLD);
+
+        store.compact().unwrap();
+
+        assert!(fs::metadata(&path).unwrap().len() < len * 2);
+
+        store.clear().unwrap();
+
+        let store = FileStore::open(&path).unwrap();
+
+        assert_eq!(store.state, MemoryStore::default());
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_file_store_corrupt() {
+        let path = temp_path("corrupt");
+        let offset;
+
+        {
+            let mut store = FileStore::open(&path).unwrap();
+
+            store.put_outgoing(1, &publish("first", QoS::ExactlyOnce, false)).unwrap();
+
+            offset = fs::metadata(&path).unwrap().len();
+
+            store.put_outgoing(2, &publish("second", QoS::ExactlyOnce, false)).unwrap();
+            store.put_outgoing(3, &publish("third", QoS::ExactlyOnce, false)).unwrap();
+        }
+
+        let mut buf = fs::read(&path).unwrap();
+
+        // a bad record in the middle of the log must not silently drop the later ones
+        buf[offset as usize + 8] ^= 0xFF;
+        fs::write(&path, &buf).unwrap();
+
+        match FileStore::open(&path) {
+            Err(Error(ErrorKind::CorruptStore(at), _)) => assert_eq!(at, offset),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        // so must a corrupt length of a record in the middle of the log
+        buf[offset as usize + 8] ^= 0xFF;
+
+        for &bit in &[0x01, 0x80] {
+            buf[offset as usize] ^= bit;
+            fs::write(&path, &buf).unwrap();
+
+            match FileStore::open(&path) {
+                Err(Error(ErrorKind::CorruptStore(at), _)) => assert_eq!(at, offset),
+                res => panic!("unexpected result {:?}", res),
+            }
+
+            buf[offset as usize] ^= bit;
+        }
+
+        // while a short or bad last record is a torn write
+        fs::write(&path, &buf[..buf.len() - 3]).unwrap();
+
+        assert_eq!(
+            FileStore::open(&path).unwrap().outgoing().unwrap(),
+            vec![
+                (1, publish("first", QoS::ExactlyOnce, false)),
+                (2, publish("second", QoS::ExactlyOnce, false)),
+            ]
+        );
+
+        let last = buf.len() - 1;
+
+        buf[last] ^= 0xFF;
+        fs::write(&path, &buf).unwrap();
+
+        let store = FileStore::open(&path).unwrap();
+
+        assert_eq!(
+            store.outgoing().unwrap(),
+            vec![
+                (1, publish("first", QoS::ExactlyOnce, false)),
+                (2, publish("second", QoS::ExactlyOnce, false)),
+            ]
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_session_restore() {
+        let path = temp_path("restore");
+
+        {
+            let mut session = Session::with_store(FileStore::open(&path).unwrap()).unwrap();
+
+            connect(&mut session, false);
+
+            session
+                .publish(Message::new("topic", "first", QoS::AtLeastOnce))
+                .unwrap();
+            session
+                .publish(Message::new("topic", "second", QoS::ExactlyOnce))
+                .unwrap();
+            session
+                .publish(Message::new("topic", "third", QoS::AtLeastOnce))
+                .unwrap();
+            session
+                .handle_packet(&Packet::PublishReceived { packet_id: 2 }, Instant::now())
+                .unwrap();
+            session
+                .handle_packet(&Packet::PublishAck { packet_id: 3 }, Instant::now())
+                .unwrap();
+
+            receive(&mut session, 9, false);
+        }
+
+        // the restarted process resends what the server hasn't acknowledged
+        let mut session = Session::with_store(FileStore::open(&path).unwrap()).unwrap();
+
+        assert_eq!(session.in_flight(), 2);
+        assert_eq!(
+            connect(&mut session, true),
+            vec![
+                Outgoing::Publish {
+                    dup: true,
+                    retain: false,
+                    packet_id: Some(1),
+                    msg: Message::new("topic", "first", QoS::AtLeastOnce),
+                },
+                Outgoing::PublishRelease(2),
+            ]
+        );
+
+        while session.poll_event().is_some() {}
+
+        // and still knows the QoS 2 message it has received
+        receive(&mut session, 9, true);
+
+        assert_eq!(session.poll_event(), None);
+        assert_eq!(
+            session
+                .publish(Message::new("topic", "fourth", QoS::AtLeastOnce))
+                .unwrap(),
+            Some(4)
+        );
+
+        // a clean session forgets everything
+        session.connect(ConnectOptions::default()).unwrap();
+
+        drop(session);
+
+        let store = FileStore::open(&path).unwrap();
+
+        assert_eq!(store.outgoing().unwrap(), vec![]);
+        assert_eq!(store.received().unwrap(), vec![]);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_session_store_failure() {
+        let mut session = Session::with_store(FullStore(MemoryStore::new())).unwrap();
+
+        connect(&mut session, false);
+
+        // a message which can't be saved is neither sent nor tracked
+        assert!(session.publish(Message::new("topic", "data", QoS::AtLeastOnce)).is_err());
+        assert_eq!(session.in_flight(), 0);
+        assert_eq!(session.poll_packet(), None);
+
+        assert_eq!(
+            session.publish(Message::new("topic", "data", QoS::AtMostOnce)).unwrap(),
+            None
+        );
+
+        // nor is a QoS 2 message acknowledged before it has been saved
+        let res = session.handle_packet(
+            &Packet::Publish {
+                dup: false,
+                retain: false,
+                qos: QoS::ExactlyOnce,
+                topic: "topic",
+                packet_id: Some(1),
+                payload: b"data",
+            },
+            Instant::now(),
+        );
+
+        assert!(res.is_err());
+        assert_eq!(session.poll_event(), None);
+        assert_eq!(session.poll_packet().unwrap().packet().packet_type(), PUBLISH);
+        assert_eq!(session.poll_packet(), None);
+    }
+}
@@ -0,0 +1,355 @@
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+
+use topic::{Level, Topic, TopicTree};
+
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// The number of leading levels which select the shard of a filter.
+pub const SHARD_DEPTH: usize = 2;
+
+/// Topic tree shared between threads, optimized for read-heavy matching.
+///
+/// The filters are sharded behind read-write locks by their literal prefix,
+/// the levels before their first wildcard, up to `SHARD_DEPTH` levels,
+/// so that `site/1/#` and `site/2/#` usually land in different shards.
+/// Matching a topic only takes, one at a time, the read locks of the shards
+/// of its first `SHARD_DEPTH` prefixes, so it runs concurrently with other matches
+/// and with changes to other shards.
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// use mqtt::{ConcurrentTopicTree, Topic};
+///
+/// let tree = Arc::new(ConcurrentTopicTree::new());
+///
+/// tree.insert(&"sport/+/player1".parse().unwrap(), 1);
+///
+/// let t = tree.clone();
+///
+/// thread::spawn(move || {
+///     t.insert(&"sport/#".parse().unwrap(), 2);
+/// }).join().unwrap();
+///
+/// let mut values = tree.matches(&"sport/tennis/player1".parse().unwrap())
+///     .into_iter()
+///     .map(|(_, value)| value)
+///     .collect::<Vec<_>>();
+///
+/// values.sort();
+///
+/// assert_eq!(values, vec![1, 2]);
+/// ```
+#[derive(Debug)]
+pub struct ConcurrentTopicTree<T = ()> {
+    shards: Vec<RwLock<TopicTree<T>>>,
+}
+
+impl<T> Default for ConcurrentTopicTree<T> {
+    fn default() -> Self {
+        ConcurrentTopicTree::with_shards(DEFAULT_SHARDS)
+    }
+}
+
+impl<T> ConcurrentTopicTree<T> {
+    pub fn new() -> Self {
+        ConcurrentTopicTree::default()
+    }
+
+    pub fn with_shards(shards: usize) -> Self {
+        ConcurrentTopicTree {
+            shards: (0..shards.max(1)).map(|_| RwLock::new(TopicTree::new())).collect(),
+        }
+    }
+
+    fn shard(&self, prefix: &[Level]) -> usize {
+        let mut hasher = DefaultHasher::new();
+
+        prefix.hash(&mut hasher);
+
+        (hasher.finish() as usize) % self.shards.len()
+    }
+
+    // The shard of a filter, selected by its literal prefix.
+    fn filter_shard(&self, topic: &Topic) -> &RwLock<TopicTree<T>> {
+        let prefix = topic
+            .iter()
+            .take(SHARD_DEPTH)
+            .take_while(|level| !level.is_wildcard())
+            .count();
+
+        &self.shards[self.shard(&topic[..prefix])]
+    }
+
+    // The shards which may hold a filter matching the topic, one for each prefix length.
+    fn match_shards(&self, topic: &Topic) -> Vec<usize> {
+        let mut shards = (0..topic.len().min(SHARD_DEPTH) + 1)
+            .map(|len| self.shard(&topic[..len]))
+            .collect::<Vec<_>>();
+
+        shards.sort();
+        shards.dedup();
+        shards
+    }
+
+    #[inline]
+    fn read(&self, topic: &Topic) -> RwLockReadGuard<'_, TopicTree<T>> {
+        self.filter_shard(topic).read().unwrap()
+    }
+
+    #[inline]
+    fn write(&self, topic: &Topic) -> RwLockWriteGuard<'_, TopicTree<T>> {
+        self.filter_shard(topic).write().unwrap()
+    }
+
+    /// The number of topic filters in the tree
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, topic: &Topic) -> bool {
+        self.read(topic).contains(topic)
+    }
+
+    /// Insert the topic filter with the value, returns the old value if the filter exists.
+    pub fn insert(&self, topic: &Topic, value: T) -> Option<T> {
+        self.write(topic).insert(topic, value)
+    }
+
+    /// Remove the topic filter, returns the attached value if the filter exists.
+    pub fn remove(&self, topic: &Topic) -> Option<T> {
+        self.write(topic).remove(topic)
+    }
+
+    /// Update the value attached to the topic filter if exists.
+    pub fn update<F, R>(&self, topic: &Topic, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        self.write(topic).get_mut(topic).map(f)
+    }
+
+    /// Match the topic to all its filters
+    pub fn match_topic(&self, topic: &Topic) -> Vec<Topic> {
+        let mut topics = Vec::new();
+
+        self.for_each_match(topic, |topic, _| topics.push(topic.clone()));
+
+        topics
+    }
+
+    /// Visit all the filters and their values which match the topic
+    pub fn for_each_match<F>(&self, topic: &Topic, mut f: F)
+    where
+        F: FnMut(&Topic, &T),
+    {
+        for shard in self.match_shards(topic) {
+            for (topic, value) in self.shards[shard].read().unwrap().matches(topic) {
+                f(topic, value)
+            }
+        }
+    }
+}
+
+impl<T: Default> ConcurrentTopicTree<T> {
+    /// Add the topic filter with a default value if not exists, and update the attached value.
+    pub fn add<F, R>(&self, topic: &Topic, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        f(self.write(topic).add(topic))
+    }
+}
+
+impl<T: Clone> ConcurrentTopicTree<T> {
+    /// Match the topic to all its filters and their values
+    pub fn matches(&self, topic: &Topic) -> Vec<(Topic, T)> {
+        let mut matches = Vec::new();
+
+        self.for_each_match(topic, |topic, value| matches.push((topic.clone(), value.clone())));
+
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_concurrent_topic_tree() {
+        let tree = ConcurrentTopicTree::with_shards(4);
+
+        assert!(tree.is_empty());
+
+        assert_eq!(tree.insert(&topic!("sport/tennis/+"), 1), None);
+        assert_eq!(tree.insert(&topic!("sport/#"), 2), None);
+        assert_eq!(tree.insert(&topic!("+/tennis/#"), 3), None);
+        assert_eq!(tree.insert(&topic!("#"), 4), None);
+        assert_eq!(tree.insert(&topic!("$SYS/#"), 5), None);
+        assert_eq!(tree.insert(&topic!("sport/#"), 6), Some(2));
+
+        assert_eq!(tree.len(), 5);
+        assert!(tree.contains(&topic!("+/tennis/#")));
+        assert!(!tree.contains(&topic!("+/tennis/+")));
+
+        let mut values = tree.matches(&topic!("sport/tennis/player1"))
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect::<Vec<_>>();
+
+        values.sort();
+
+        assert_eq!(values, vec![1, 3, 4, 6]);
+
+        assert_eq!(tree.matches(&topic!("$SYS/monitor")), vec![(topic!("$SYS/#"), 5)]);
+
+        assert_eq!(tree.update(&topic!("#"), |value| {
+            *value += 10;
+            *value
+        }),
+                   Some(14));
+        assert_eq!(tree.update(&topic!("sport/+"), |value| *value), None);
+
+        assert_eq!(tree.remove(&topic!("#")), Some(14));
+        assert_eq!(tree.remove(&topic!("#")), None);
+        assert_eq!(tree.match_topic(&topic!("golf")), vec![]);
+
+        let tree: ConcurrentTopicTree<HashSet<&str>> = ConcurrentTopicTree::new();
+
+        assert!(tree.add(&topic!("sport/#"), |clients| clients.insert("client1")));
+        assert!(tree.add(&topic!("sport/#"), |clients| clients.insert("client2")));
+        assert!(!tree.add(&topic!("sport/#"), |clients| clients.insert("client1")));
+
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.matches(&topic!("sport/tennis"))[0].1.len(), 2);
+    }
+
+    #[test]
+    fn test_concurrent_topic_tree_shared_root() {
+        let tree = ConcurrentTopicTree::with_shards(8);
+
+        for n in 0..64 {
+            tree.insert(&topic!(&format!("site/{}/sensor/temp", n)), n);
+        }
+
+        tree.insert(&topic!("site/+/sensor/temp"), 100);
+        tree.insert(&topic!("site/#"), 101);
+        tree.insert(&topic!("+/+/sensor/#"), 102);
+        tree.insert(&topic!("site"), 103);
+
+        // the filters under a common root are spread over the shards
+        let used = tree.shards
+            .iter()
+            .filter(|shard| !shard.read().unwrap().is_empty())
+            .count();
+
+        assert!(used >= 4, "only {} shards used", used);
+        assert_eq!(tree.len(), 68);
+
+        let mut values = tree.matches(&topic!("site/7/sensor/temp"))
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect::<Vec<_>>();
+
+        values.sort();
+
+        assert_eq!(values, vec![7, 100, 101, 102]);
+
+        let mut values = tree.matches(&topic!("site"))
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect::<Vec<_>>();
+
+        values.sort();
+
+        assert_eq!(values, vec![101, 103]);
+        assert!(tree.match_shards(&topic!("site/7/sensor/temp")).len() <= SHARD_DEPTH + 1);
+    }
+
+    #[test]
+    fn test_concurrent_topic_tree_stress() {
+        const READERS: usize = 4;
+        const WRITERS: usize = 4;
+        const ROUNDS: usize = 500;
+
+        let tree = Arc::new(ConcurrentTopicTree::with_shards(8));
+        let done = Arc::new(AtomicBool::new(false));
+
+        tree.insert(&topic!("sport/#"), 0);
+        tree.insert(&topic!("+/tennis/+"), 0);
+        tree.insert(&topic!("sport/tennis/player1"), 0);
+
+        let readers = (0..READERS)
+            .map(|_| {
+                let tree = tree.clone();
+                let done = done.clone();
+
+                thread::spawn(move || {
+                    let topic = topic!("sport/tennis/player1");
+                    let mut matches = 0;
+
+                    while !done.load(Ordering::SeqCst) {
+                        let topics = tree.match_topic(&topic);
+
+                        assert!(topics.contains(&topic!("sport/#")));
+                        assert!(topics.contains(&topic!("+/tennis/+")));
+                        assert!(topics.contains(&topic!("sport/tennis/player1")));
+
+                        matches += 1;
+                    }
+
+                    matches
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let writers = (0..WRITERS)
+            .map(|n| {
+                let tree = tree.clone();
+
+                thread::spawn(move || for i in 0..ROUNDS {
+                    let filters = [format!("sport/tennis/player{}-{}", n, i),
+                                   format!("+/tennis/{}/{}", n, i),
+                                   format!("client{}/{}/#", n, i)];
+
+                    for filter in &filters {
+                        assert_eq!(tree.insert(&topic!(filter), i), None);
+                    }
+
+                    for filter in &filters {
+                        assert!(tree.contains(&topic!(filter)));
+                        assert_eq!(tree.remove(&topic!(filter)), Some(i));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        done.store(true, Ordering::SeqCst);
+
+        for reader in readers {
+            assert!(reader.join().unwrap() > 0);
+        }
+
+        assert_eq!(tree.len(), 3);
+    }
+}
@@ -4,9 +4,30 @@ use byteorder::{BigEndian, WriteBytesExt};
 
 use proto::*;
 use packet::*;
+use topic::{is_valid_topic_name, is_valid_topic_filter};
 
 pub const MAX_VARIABLE_LENGTH: usize = 268435455; // 0xFF,0xFF,0xFF,0x7F
 
+fn check_topics(packet: &Packet) -> Result<()> {
+    let valid = match *packet {
+        Packet::Connect { last_will: Some(LastWill { topic, .. }), .. } |
+        Packet::Publish { topic, .. } => is_valid_topic_name(topic),
+        Packet::Subscribe { ref topic_filters, .. } => {
+            topic_filters.iter().all(|&(filter, _)| is_valid_topic_filter(filter))
+        }
+        Packet::Unsubscribe { ref topic_filters, .. } => {
+            topic_filters.iter().all(|filter| is_valid_topic_filter(filter))
+        }
+        _ => true,
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::new(ErrorKind::InvalidInput, "invalid topic"))
+    }
+}
+
 pub trait WritePacketHelper: io::Write {
     #[inline]
     fn write_fixed_header(&mut self, packet: &Packet) -> Result<usize> {
@@ -306,7 +327,11 @@ pub trait WritePacketHelper: io::Write {
 pub trait WritePacketExt: io::Write {
     #[inline]
     /// Writes packet to the underlying writer.
+    ///
+    /// Fails with `InvalidInput` if a topic name or topic filter in the packet is invalid.
     fn write_packet(&mut self, packet: &Packet) -> Result<usize> {
+        check_topics(packet)?;
+
         Ok(
             self.write_fixed_header(packet)? + self.write_content(packet)?,
         )
@@ -488,6 +513,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_encode_invalid_topics() {
+        let mut v = Vec::new();
+
+        let p = Packet::Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: "sport/+",
+            packet_id: None,
+            payload: b"data",
+        };
+
+        assert_eq!(v.write_packet(&p).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(v.is_empty());
+
+        let p = Packet::Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: "",
+            packet_id: None,
+            payload: b"data",
+        };
+
+        assert!(v.write_packet(&p).is_err());
+
+        let p = Packet::Subscribe {
+            packet_id: 0x1234,
+            topic_filters: vec![("sport/+", QoS::AtLeastOnce), ("sport/#/player1", QoS::ExactlyOnce)],
+        };
+
+        assert!(v.write_packet(&p).is_err());
+
+        let p = Packet::Unsubscribe {
+            packet_id: 0x1234,
+            topic_filters: vec!["sport\0"],
+        };
+
+        assert!(v.write_packet(&p).is_err());
+    }
+
     #[test]
     fn test_encode_ping_packets() {
         assert_packet!(Packet::PingRequest, b"\xc0\x00");
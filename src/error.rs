@@ -4,6 +4,7 @@ error_chain!{
     foreign_links {
         Fmt(::std::fmt::Error);
         Io(::std::io::Error);
+        Url(::url::ParseError);
     }
 
     errors {
@@ -11,6 +12,27 @@ error_chain!{
         InvalidState
         InvalidPacket
         InvalidTopic
+        InvalidPattern
+        InvalidCapture
+        InvalidSnapshot
+        CorruptStore(offset: u64) {
+            description("corrupt session store")
+            display("corrupt session store record at offset {}", offset)
+        }
+        InvalidUrl(reason: String) {
+            description("invalid url")
+            display("invalid url, {}", reason)
+        }
+        PacketIdExhausted
+        ConnectionRefused(code: ::packet::ConnectReturnCode) {
+            description("connection refused")
+            display("connection refused, {}", code.reason())
+        }
+        ConnectionClosed
+        Timeout
+        DeliveryFailed
+        QueueFull
+        WouldBlock
         SpawnError
     }
 }
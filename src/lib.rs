@@ -11,12 +11,21 @@ extern crate byteorder;
 extern crate bytes;
 extern crate slab;
 extern crate rotor;
+extern crate url;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tokio")]
+extern crate futures_core;
 
 mod error;
 #[macro_use]
 mod topic;
 #[macro_use]
 mod proto;
+mod pattern;
+mod concurrent;
+mod snapshot;
+mod rewrite;
 mod packet;
 mod encode;
 mod decode;
@@ -25,11 +34,18 @@ pub mod transport;
 pub mod server;
 pub mod client;
 
-pub use proto::QoS;
-pub use topic::{Level, Topic, TopicTree, MatchTopic};
+pub use proto::{Protocol, QoS, ClientId, Message, PacketId};
+pub use topic::{Level, Topic, TopicName, TopicFilter, TopicTree, MatchTopic, Interner, Stats,
+                MAX_TOPIC_LEN,
+                is_valid_topic_name, is_valid_topic_filter};
+pub use concurrent::ConcurrentTopicTree;
+pub use snapshot::SnapshotValue;
+pub use rewrite::{RewriteRule, Rewriter};
+pub use pattern::{Pattern, Segment, Captures, FromCaptures};
 pub use packet::{Packet, LastWill, ConnectReturnCode, SubscribeReturnCode};
 pub use encode::WritePacketExt;
 pub use decode::{ReadPacketExt, read_packet};
+pub use error::{Error, ErrorKind, Result};
 
 // http://www.iana.org/assignments/service-names-port-numbers/service-names-port-numbers.xhtml
 pub const TCP_PORT: u16 = 1883;
@@ -0,0 +1,399 @@
+use std::fmt::{self, Display, Formatter, Write};
+use std::str::FromStr;
+use std::ops::Deref;
+use std::collections::HashMap;
+
+use topic::{Level, Topic};
+use error::*;
+use error::ErrorKind::*;
+
+/// A segment of a topic pattern
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub enum Segment {
+    /// A plain topic level, including the unnamed `+` and `#` wildcards
+    Level(Level),
+    /// Named single level capture `{name}`, behaves like `+`
+    Single(String),
+    /// Named multi-level capture `{#name}`, behaves like a trailing `#`
+    Multi(String),
+}
+
+/// Topic pattern with named captures, like `devices/{device_id}/telemetry/{kind}`
+///
+/// ```
+/// use mqtt::{Pattern, Topic};
+///
+/// let pattern: Pattern = "devices/{device_id}/telemetry/{#kind}".parse().unwrap();
+///
+/// assert_eq!(pattern.filter().to_string(), "devices/+/telemetry/#");
+///
+/// let captures = pattern.captures(&"devices/gw-1/telemetry/temp/avg".parse().unwrap()).unwrap();
+///
+/// assert_eq!(captures.get("device_id").map(|s| s.as_str()), Some("gw-1"));
+/// assert_eq!(captures.get("kind").map(|s| s.as_str()), Some("temp/avg"));
+/// ```
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub struct Pattern(Vec<Segment>);
+
+impl Pattern {
+    #[inline]
+    pub fn segments(&self) -> &[Segment] {
+        &self.0
+    }
+
+    /// The names of captures in the pattern
+    pub fn names(&self) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter_map(|segment| match *segment {
+                Segment::Single(ref name) | Segment::Multi(ref name) => Some(name.as_str()),
+                Segment::Level(_) => None,
+            })
+            .collect()
+    }
+
+    /// Convert to the MQTT subscription filter, replace captures with wildcards
+    pub fn filter(&self) -> Topic {
+        self.0
+            .iter()
+            .map(|segment| match *segment {
+                Segment::Level(ref level) => level.clone(),
+                Segment::Single(_) => Level::SingleWildcard,
+                Segment::Multi(_) => Level::MultiWildcard,
+            })
+            .collect::<Vec<Level>>()
+            .into()
+    }
+
+    /// Extract the captures from a matching topic
+    pub fn captures(&self, topic: &Topic) -> Option<Captures> {
+        self.capture_levels(topic).map(|levels| {
+            Captures(levels
+                .into_iter()
+                .map(|(name, levels)| {
+                    let value = levels.iter().map(|level| level.to_string()).collect::<Vec<_>>();
+
+                    (name.to_owned(), value.join("/"))
+                })
+                .collect())
+        })
+    }
+
+    /// Extract the captured levels from a matching topic
+    ///
+    /// The literal levels of the pattern must be the same as the levels of the topic,
+    /// a named single level capture takes any level except `#`,
+    /// and a named multi-level capture takes all the remaining levels.
+    pub fn capture_levels<'a>(&'a self, topic: &'a Topic) -> Option<Vec<(&'a str, &'a [Level])>> {
+        let mut captures = Vec::new();
+        let mut pos = 0;
+
+        for segment in &self.0 {
+            match *segment {
+                Segment::Level(Level::MultiWildcard) | Segment::Multi(_) => {
+                    let rest = &topic[pos..];
+
+                    if rest.first().is_some_and(|level| level.is_metadata()) {
+                        return None;
+                    }
+
+                    if let Segment::Multi(ref name) = *segment {
+                        captures.push((name.as_str(), rest));
+                    }
+
+                    return Some(captures);
+                }
+                Segment::Level(Level::SingleWildcard) | Segment::Single(_) => {
+                    match topic.get(pos) {
+                        Some(level) if !level.is_metadata() && *level != Level::MultiWildcard => {
+                            if let Segment::Single(ref name) = *segment {
+                                captures.push((name.as_str(), &topic[pos..pos + 1]));
+                            }
+                        }
+                        _ => return None,
+                    }
+                }
+                Segment::Level(ref expected) => {
+                    if topic.get(pos) != Some(expected) {
+                        return None;
+                    }
+                }
+            }
+
+            pos += 1;
+        }
+
+        if pos == topic.len() {
+            Some(captures)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn is_match(&self, topic: &Topic) -> bool {
+        self.captures(topic).is_some()
+    }
+}
+
+impl FromStr for Segment {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with('{') && s.ends_with('}') && s.len() > 1 {
+            let (multi, name) = match &s[1..s.len() - 1] {
+                name if name.starts_with('#') => (true, &name[1..]),
+                name => (false, name),
+            };
+
+            if name.is_empty() || name.contains(|c| "{}+#$".contains(c)) {
+                bail!(InvalidPattern)
+            }
+
+            Ok(if multi {
+                Segment::Multi(name.to_owned())
+            } else {
+                Segment::Single(name.to_owned())
+            })
+        } else if s.contains(['{', '}']) {
+            bail!(InvalidPattern)
+        } else {
+            Level::from_str(s).map(Segment::Level)
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let segments = s.split('/').map(Segment::from_str).collect::<Result<Vec<_>>>()?;
+
+        let pattern = Pattern(segments);
+
+        let mut names = pattern.names();
+        let count = names.len();
+
+        names.sort();
+        names.dedup();
+
+        if names.len() != count || !pattern.filter().is_valid() {
+            bail!(InvalidPattern)
+        }
+
+        Ok(pattern)
+    }
+}
+
+impl Display for Segment {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Segment::Level(ref level) => level.fmt(f),
+            Segment::Single(ref name) => write!(f, "{{{}}}", name),
+            Segment::Multi(ref name) => write!(f, "{{#{}}}", name),
+        }
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut first = true;
+
+        for segment in &self.0 {
+            if first {
+                first = false;
+            } else {
+                f.write_char('/')?;
+            }
+
+            segment.fmt(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Named values captured from a topic
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct Captures(HashMap<String, String>);
+
+impl Captures {
+    /// Parse the named capture as `T`
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<T> {
+        match self.0.get(name).map(|value| value.parse()) {
+            Some(Ok(value)) => Ok(value),
+            _ => bail!(InvalidCapture),
+        }
+    }
+
+    /// Extract the captures into a typed struct
+    pub fn extract<T: FromCaptures>(&self) -> Result<T> {
+        T::from_captures(self)
+    }
+}
+
+impl Deref for Captures {
+    type Target = HashMap<String, String>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<Captures> for HashMap<String, String> {
+    fn from(captures: Captures) -> Self {
+        captures.0
+    }
+}
+
+/// Build a typed value from the captures of a `Pattern`
+///
+/// ```
+/// use mqtt::{Pattern, Captures, FromCaptures};
+///
+/// struct Telemetry {
+///     device_id: String,
+///     channel: u8,
+/// }
+///
+/// impl FromCaptures for Telemetry {
+///     fn from_captures(captures: &Captures) -> mqtt::Result<Self> {
+///         Ok(Telemetry {
+///             device_id: captures.parse("device_id")?,
+///             channel: captures.parse("channel")?,
+///         })
+///     }
+/// }
+///
+/// let pattern: Pattern = "devices/{device_id}/telemetry/{channel}".parse().unwrap();
+/// let telemetry: Telemetry = pattern
+///     .captures(&"devices/gw-1/telemetry/3".parse().unwrap())
+///     .unwrap()
+///     .extract()
+///     .unwrap();
+///
+/// assert_eq!(telemetry.device_id, "gw-1");
+/// assert_eq!(telemetry.channel, 3);
+/// ```
+pub trait FromCaptures: Sized {
+    fn from_captures(captures: &Captures) -> Result<Self>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pattern() {
+        let p: Pattern = "devices/{device_id}/telemetry/{kind}".parse().unwrap();
+
+        assert_eq!(p.segments(),
+                   &[Segment::Level(Level::normal("devices").unwrap()),
+                     Segment::Single("device_id".to_owned()),
+                     Segment::Level(Level::normal("telemetry").unwrap()),
+                     Segment::Single("kind".to_owned())]);
+        assert_eq!(p.names(), vec!["device_id", "kind"]);
+        assert_eq!(p.to_string(), "devices/{device_id}/telemetry/{kind}");
+
+        let p: Pattern = "$SYS/+/{#rest}".parse().unwrap();
+
+        assert_eq!(p.segments(),
+                   &[Segment::Level(Level::metadata("$SYS").unwrap()),
+                     Segment::Level(Level::SingleWildcard),
+                     Segment::Multi("rest".to_owned())]);
+        assert_eq!(p.to_string(), "$SYS/+/{#rest}");
+
+        assert!("devices/{}".parse::<Pattern>().is_err());
+        assert!("devices/{id".parse::<Pattern>().is_err());
+        assert!("devices/x{id}".parse::<Pattern>().is_err());
+        assert!("devices/{id}/{id}".parse::<Pattern>().is_err());
+        assert!("devices/{#rest}/telemetry".parse::<Pattern>().is_err());
+        assert!("devices/#/telemetry".parse::<Pattern>().is_err());
+        assert!("devices/{$SYS}".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn test_pattern_filter() {
+        let p: Pattern = "devices/{device_id}/telemetry/{#kind}".parse().unwrap();
+
+        assert_eq!(p.filter(), topic!("devices/+/telemetry/#"));
+
+        let p: Pattern = "sport/tennis/player1".parse().unwrap();
+
+        assert_eq!(p.filter(), topic!("sport/tennis/player1"));
+    }
+
+    #[test]
+    fn test_pattern_captures() {
+        let p: Pattern = "devices/{device_id}/telemetry/{kind}".parse().unwrap();
+
+        let captures = p.captures(&topic!("devices/gw-1/telemetry/temp")).unwrap();
+
+        assert_eq!(captures.len(), 2);
+        assert_eq!(captures["device_id"], "gw-1");
+        assert_eq!(captures["kind"], "temp");
+
+        assert!(p.captures(&topic!("devices/gw-1/telemetry")).is_none());
+        assert!(p.captures(&topic!("devices/gw-1/telemetry/temp/avg")).is_none());
+        assert!(p.captures(&topic!("devices/gw-1/status/temp")).is_none());
+
+        let captures = p.captures(&topic!("devices//telemetry/temp")).unwrap();
+
+        assert_eq!(captures["device_id"], "");
+
+        let p: Pattern = "devices/{device_id}/{#rest}".parse().unwrap();
+
+        let captures = p.captures(&topic!("devices/gw-1/telemetry/temp")).unwrap();
+
+        assert_eq!(captures["device_id"], "gw-1");
+        assert_eq!(captures["rest"], "telemetry/temp");
+
+        let captures = p.captures(&topic!("devices/gw-1")).unwrap();
+
+        assert_eq!(captures["rest"], "");
+
+        let p: Pattern = "{root}/{#rest}".parse().unwrap();
+
+        assert!(p.is_match(&topic!("sport/tennis")));
+        assert!(!p.is_match(&topic!("$SYS/monitor")));
+
+        let p: Pattern = "$SYS/{#rest}".parse().unwrap();
+
+        assert_eq!(p.captures(&topic!("$SYS/monitor/Clients")).unwrap()["rest"],
+                   "monitor/Clients");
+    }
+
+    #[test]
+    fn test_extract_captures() {
+        #[derive(Debug, PartialEq)]
+        struct Telemetry {
+            device_id: String,
+            channel: u8,
+        }
+
+        impl FromCaptures for Telemetry {
+            fn from_captures(captures: &Captures) -> Result<Self> {
+                Ok(Telemetry {
+                    device_id: captures.parse("device_id")?,
+                    channel: captures.parse("channel")?,
+                })
+            }
+        }
+
+        let p: Pattern = "devices/{device_id}/telemetry/{channel}".parse().unwrap();
+
+        assert_eq!(p.captures(&topic!("devices/gw-1/telemetry/3"))
+                       .unwrap()
+                       .extract::<Telemetry>()
+                       .unwrap(),
+                   Telemetry {
+                       device_id: "gw-1".to_owned(),
+                       channel: 3,
+                   });
+
+        assert!(p.captures(&topic!("devices/gw-1/telemetry/temp"))
+                    .unwrap()
+                    .extract::<Telemetry>()
+                    .is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt::{self, Display, Formatter};
 use std::ops::Deref;
 
+use bytes::Bytes;
 use rand::{thread_rng, Rng};
 
 #[macro_export]
@@ -87,6 +88,18 @@ impl ClientId {
     }
 }
 
+impl<'a> From<&'a str> for ClientId {
+    fn from(s: &'a str) -> Self {
+        ClientId(s.to_owned())
+    }
+}
+
+impl From<String> for ClientId {
+    fn from(s: String) -> Self {
+        ClientId(s)
+    }
+}
+
 impl Deref for ClientId {
     type Target = str;
 
@@ -103,9 +116,20 @@ impl Display for ClientId {
 
 pub type PacketId = u16;
 
+/// An application message with its own copy of the topic and payload.
 #[derive(Debug, PartialEq, Clone)]
-pub struct Message<'a> {
-    pub topic: &'a str,
-    pub payload: &'a [u8],
+pub struct Message {
+    pub topic: String,
+    pub payload: Bytes,
     pub qos: QoS,
 }
+
+impl Message {
+    pub fn new<T: Into<String>, P: Into<Bytes>>(topic: T, payload: P, qos: QoS) -> Message {
+        Message {
+            topic: topic.into(),
+            payload: payload.into(),
+            qos,
+        }
+    }
+}
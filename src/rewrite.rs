@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use topic::Topic;
+use pattern::{Pattern, Segment};
+use error::*;
+use error::ErrorKind::*;
+
+/// Rewrite rule from a pattern with named captures to a template
+///
+/// The template uses the same syntax as `Pattern`, with its captures
+/// replaced by the levels captured by the pattern.
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub struct RewriteRule {
+    pattern: Pattern,
+    template: Pattern,
+}
+
+impl RewriteRule {
+    pub fn new(pattern: &str, template: &str) -> Result<RewriteRule> {
+        let pattern: Pattern = pattern.parse()?;
+        let template: Pattern = template.parse()?;
+
+        RewriteRule::check(&pattern, &template)?;
+
+        Ok(RewriteRule { pattern, template })
+    }
+
+    fn check(pattern: &Pattern, template: &Pattern) -> Result<()> {
+        let names = pattern.names();
+
+        for segment in template.segments() {
+            match *segment {
+                Segment::Level(ref level) if level.is_wildcard() => bail!(InvalidPattern),
+                Segment::Single(ref name) |
+                Segment::Multi(ref name) if !names.contains(&name.as_str()) => bail!(InvalidPattern),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn pattern(&self) -> &Pattern {
+        &self.pattern
+    }
+
+    #[inline]
+    pub fn template(&self) -> &Pattern {
+        &self.template
+    }
+
+    /// The rule rewrites the topics back, fails if the template doesn't capture all the levels.
+    pub fn reverse(&self) -> Result<RewriteRule> {
+        if self.pattern.segments().iter().any(|segment| match *segment {
+            Segment::Level(ref level) => level.is_wildcard(),
+            _ => false,
+        }) {
+            bail!(InvalidPattern)
+        }
+
+        RewriteRule::check(&self.template, &self.pattern)?;
+
+        Ok(RewriteRule {
+            pattern: self.template.clone(),
+            template: self.pattern.clone(),
+        })
+    }
+
+    /// Rewrite the topic name or topic filter if it matches the pattern.
+    pub fn rewrite(&self, topic: &Topic) -> Option<Topic> {
+        let captures = self.pattern.capture_levels(topic)?.into_iter().collect::<HashMap<_, _>>();
+        let mut levels = Vec::with_capacity(topic.len() + self.template.segments().len());
+
+        for segment in self.template.segments() {
+            match *segment {
+                Segment::Level(ref level) => levels.push(level.clone()),
+                Segment::Single(ref name) | Segment::Multi(ref name) => {
+                    levels.extend_from_slice(captures[name.as_str()])
+                }
+            }
+        }
+
+        let topic = Topic::from(levels);
+
+        if topic.is_valid_filter() {
+            Some(topic)
+        } else {
+            None
+        }
+    }
+}
+
+/// Ordered topic rewrite rules, the first matching rule applies.
+///
+/// ```
+/// use mqtt::{Rewriter, Topic};
+///
+/// let rewriter = Rewriter::new()
+///     .rule("tenantA/{#rest}", "global/tenantA/{#rest}").unwrap();
+///
+/// let topic: Topic = "tenantA/+/x".parse().unwrap();
+///
+/// assert_eq!(rewriter.apply(&topic).to_string(), "global/tenantA/+/x");
+/// assert_eq!(rewriter.reverse().unwrap().apply(&rewriter.apply(&topic)), topic);
+/// ```
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct Rewriter {
+    rules: Vec<RewriteRule>,
+}
+
+impl Rewriter {
+    pub fn new() -> Rewriter {
+        Rewriter::default()
+    }
+
+    /// Append a rule from the pattern to the template
+    pub fn rule(mut self, pattern: &str, template: &str) -> Result<Self> {
+        self.rules.push(RewriteRule::new(pattern, template)?);
+
+        Ok(self)
+    }
+
+    #[inline]
+    pub fn add(&mut self, rule: RewriteRule) {
+        self.rules.push(rule)
+    }
+
+    #[inline]
+    pub fn rules(&self) -> &[RewriteRule] {
+        &self.rules
+    }
+
+    /// The rules which rewrite the topics back
+    pub fn reverse(&self) -> Result<Rewriter> {
+        Ok(Rewriter {
+            rules: self.rules.iter().map(|rule| rule.reverse()).collect::<Result<_>>()?,
+        })
+    }
+
+    /// Rewrite the topic with the first matching rule
+    pub fn rewrite(&self, topic: &Topic) -> Option<Topic> {
+        self.rules.iter().filter_map(|rule| rule.rewrite(topic)).next()
+    }
+
+    /// Rewrite the topic with the first matching rule, or keep it unchanged.
+    pub fn apply(&self, topic: &Topic) -> Topic {
+        self.rewrite(topic).unwrap_or_else(|| topic.clone())
+    }
+
+    /// Rewrite the topic name or topic filter string
+    pub fn apply_str(&self, s: &str) -> Result<String> {
+        let topic: Topic = s.parse()?;
+
+        Ok(match self.rewrite(&topic) {
+            Some(topic) => topic.to_string(),
+            None => s.to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_rule() {
+        let rule = RewriteRule::new("tenantA/{#rest}", "global/tenantA/{#rest}").unwrap();
+
+        assert_eq!(rule.rewrite(&topic!("tenantA/device/x")),
+                   Some(topic!("global/tenantA/device/x")));
+        assert_eq!(rule.rewrite(&topic!("tenantA/+/x")), Some(topic!("global/tenantA/+/x")));
+        assert_eq!(rule.rewrite(&topic!("tenantA/#")), Some(topic!("global/tenantA/#")));
+        assert_eq!(rule.rewrite(&topic!("tenantA")), Some(topic!("global/tenantA")));
+        assert_eq!(rule.rewrite(&topic!("tenantA/")), Some(topic!("global/tenantA/")));
+        assert_eq!(rule.rewrite(&topic!("tenantB/+/x")), None);
+        assert_eq!(rule.rewrite(&topic!("+/+/x")), None);
+        assert_eq!(rule.rewrite(&topic!("#")), None);
+
+        let back = rule.reverse().unwrap();
+
+        assert_eq!(back.rewrite(&topic!("global/tenantA/+/x")), Some(topic!("tenantA/+/x")));
+        assert_eq!(back.rewrite(&topic!("global/tenantB/+/x")), None);
+
+        let rule = RewriteRule::new("devices/{id}/{kind}", "{kind}/by-device/{id}").unwrap();
+
+        assert_eq!(rule.rewrite(&topic!("devices/gw-1/temp")), Some(topic!("temp/by-device/gw-1")));
+        assert_eq!(rule.rewrite(&topic!("devices/+/temp")), Some(topic!("temp/by-device/+")));
+        assert_eq!(rule.rewrite(&topic!("devices/gw-1/#")), None);
+        assert_eq!(rule.rewrite(&topic!("devices/gw-1")), None);
+
+        assert!(RewriteRule::new("devices/{id}", "devices/{name}").is_err());
+        assert!(RewriteRule::new("devices/{id}", "devices/+/{id}").is_err());
+        assert!(RewriteRule::new("devices/+/{id}", "{id}").unwrap().reverse().is_err());
+        assert!(RewriteRule::new("devices/{id}/{kind}", "{id}").unwrap().reverse().is_err());
+    }
+
+    #[test]
+    fn test_rewriter() {
+        let rewriter = Rewriter::new()
+            .rule("tenantA/admin/{#rest}", "admin/{#rest}")
+            .unwrap()
+            .rule("tenantA/{#rest}", "global/tenantA/{#rest}")
+            .unwrap();
+
+        assert_eq!(rewriter.rules().len(), 2);
+
+        assert_eq!(rewriter.apply(&topic!("tenantA/admin/+")), topic!("admin/+"));
+        assert_eq!(rewriter.apply(&topic!("tenantA/sensor/+")), topic!("global/tenantA/sensor/+"));
+        assert_eq!(rewriter.apply(&topic!("tenantB/sensor/+")), topic!("tenantB/sensor/+"));
+        assert_eq!(rewriter.rewrite(&topic!("$SYS/monitor")), None);
+
+        assert_eq!(rewriter.apply_str("tenantA/sensor/temp").unwrap(),
+                   "global/tenantA/sensor/temp");
+        assert_eq!(rewriter.apply_str("tenantB/sensor/temp").unwrap(), "tenantB/sensor/temp");
+        assert!(rewriter.apply_str("tenantA/#/temp").is_err());
+
+        let back = rewriter.reverse().unwrap();
+
+        assert_eq!(back.apply(&topic!("admin/+")), topic!("tenantA/admin/+"));
+        assert_eq!(back.apply(&topic!("global/tenantA/sensor/+")), topic!("tenantA/sensor/+"));
+    }
+}
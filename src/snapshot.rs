@@ -0,0 +1,392 @@
+use std::io::{self, Read, Write, Cursor};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use topic::{Level, Topic, TopicTree};
+use error::*;
+use error::ErrorKind::*;
+
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"MQTT";
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+const LEVEL_NORMAL: u8 = 0;
+const LEVEL_METADATA: u8 = 1;
+const LEVEL_BLANK: u8 = 2;
+const LEVEL_SINGLE_WILDCARD: u8 = 3;
+const LEVEL_MULTI_WILDCARD: u8 = 4;
+
+/// Value which could be saved in a `TopicTree` snapshot
+pub trait SnapshotValue: Sized {
+    fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()>;
+
+    fn read_value<R: Read>(r: &mut R) -> io::Result<Self>;
+}
+
+impl SnapshotValue for () {
+    fn write_value<W: Write>(&self, _: &mut W) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_value<R: Read>(_: &mut R) -> io::Result<Self> {
+        Ok(())
+    }
+}
+
+impl SnapshotValue for bool {
+    fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u8(*self as u8)
+    }
+
+    fn read_value<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(r.read_u8()? != 0)
+    }
+}
+
+impl SnapshotValue for u8 {
+    fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u8(*self)
+    }
+
+    fn read_value<R: Read>(r: &mut R) -> io::Result<Self> {
+        r.read_u8()
+    }
+}
+
+macro_rules! snapshot_int {
+    ($ty:ty, $write:ident, $read:ident) => {
+        impl SnapshotValue for $ty {
+            fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()> {
+                w.$write::<BigEndian>(*self)
+            }
+
+            fn read_value<R: Read>(r: &mut R) -> io::Result<Self> {
+                r.$read::<BigEndian>()
+            }
+        }
+    }
+}
+
+snapshot_int!(u16, write_u16, read_u16);
+snapshot_int!(u32, write_u32, read_u32);
+snapshot_int!(u64, write_u64, read_u64);
+snapshot_int!(i32, write_i32, read_i32);
+snapshot_int!(i64, write_i64, read_i64);
+
+impl SnapshotValue for Vec<u8> {
+    fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u32::<BigEndian>(self.len() as u32)?;
+        w.write_all(self)
+    }
+
+    fn read_value<R: Read>(r: &mut R) -> io::Result<Self> {
+        let len = r.read_u32::<BigEndian>()? as usize;
+        let mut buf = Vec::new();
+
+        r.take(len as u64).read_to_end(&mut buf)?;
+
+        if buf.len() != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(buf)
+    }
+}
+
+impl SnapshotValue for String {
+    fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u32::<BigEndian>(self.len() as u32)?;
+        w.write_all(self.as_bytes())
+    }
+
+    fn read_value<R: Read>(r: &mut R) -> io::Result<Self> {
+        String::from_utf8(Vec::read_value(r)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+impl<T: SnapshotValue> SnapshotValue for Option<T> {
+    fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match *self {
+            Some(ref value) => {
+                w.write_u8(1)?;
+                value.write_value(w)
+            }
+            None => w.write_u8(0),
+        }
+    }
+
+    fn read_value<R: Read>(r: &mut R) -> io::Result<Self> {
+        if r.read_u8()? != 0 {
+            T::read_value(r).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Checksum of the snapshot, CRC-32 (IEEE 802.3)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &b in data {
+        crc ^= u32::from(b);
+
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+impl<T: SnapshotValue> TopicTree<T> {
+    /// Save the topic filters and their values as a compact binary snapshot.
+    ///
+    /// The snapshot starts with the magic `MQTT` and a version byte,
+    /// followed by the level names table, the filters encoded as indexes to the names
+    /// and their values, and ends with the CRC-32 checksum of the preceding bytes.
+    ///
+    /// ```
+    /// use mqtt::{TopicTree, Topic};
+    ///
+    /// let mut tree = TopicTree::new();
+    ///
+    /// tree.insert(&"sport/tennis/+".parse().unwrap(), String::from("client1"));
+    ///
+    /// let mut buf = Vec::new();
+    ///
+    /// tree.save(&mut buf).unwrap();
+    ///
+    /// let tree = TopicTree::<String>::load(&buf[..]).unwrap();
+    ///
+    /// assert_eq!(tree.get(&"sport/tennis/+".parse().unwrap()).unwrap(), "client1");
+    /// ```
+    pub fn save<W: Write>(&self, mut w: W) -> Result<()> {
+        let mut names = Vec::new();
+        let mut indexes = HashMap::new();
+        let mut topics = Vec::new();
+
+        topics.write_u32::<BigEndian>(self.len() as u32)?;
+
+        for (topic, value) in self.entries() {
+            topics.write_u16::<BigEndian>(topic.len() as u16)?;
+
+            for level in topic.iter() {
+                match *level {
+                    Level::Normal(ref name) | Level::Metadata(ref name) => {
+                        let next_idx = names.len();
+                        let idx = *indexes.entry(name.clone()).or_insert(next_idx);
+
+                        if idx == next_idx {
+                            names.push(name.clone());
+                        }
+
+                        topics.write_u8(if level.is_normal() {
+                            LEVEL_NORMAL
+                        } else {
+                            LEVEL_METADATA
+                        })?;
+                        topics.write_u32::<BigEndian>(idx as u32)?;
+                    }
+                    Level::Blank => topics.write_u8(LEVEL_BLANK)?,
+                    Level::SingleWildcard => topics.write_u8(LEVEL_SINGLE_WILDCARD)?,
+                    Level::MultiWildcard => topics.write_u8(LEVEL_MULTI_WILDCARD)?,
+                }
+            }
+
+            value.write_value(&mut topics)?;
+        }
+
+        let mut buf = Vec::with_capacity(topics.len() + 64);
+
+        buf.write_all(SNAPSHOT_MAGIC)?;
+        buf.write_u8(SNAPSHOT_VERSION)?;
+        buf.write_u32::<BigEndian>(names.len() as u32)?;
+
+        for name in names {
+            buf.write_u16::<BigEndian>(name.len() as u16)?;
+            buf.write_all(name.as_bytes())?;
+        }
+
+        buf.write_all(&topics)?;
+
+        let checksum = crc32(&buf);
+
+        buf.write_u32::<BigEndian>(checksum)?;
+
+        w.write_all(&buf)?;
+
+        Ok(())
+    }
+
+    /// Load the topic filters and their values from a binary snapshot.
+    pub fn load<R: Read>(mut r: R) -> Result<TopicTree<T>> {
+        let mut buf = Vec::new();
+
+        r.read_to_end(&mut buf)?;
+
+        if buf.len() < SNAPSHOT_MAGIC.len() + 1 + 4 ||
+            &buf[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC
+        {
+            bail!(InvalidSnapshot)
+        }
+
+        if buf[SNAPSHOT_MAGIC.len()] != SNAPSHOT_VERSION {
+            warn!("unsupported snapshot version {}", buf[SNAPSHOT_MAGIC.len()]);
+
+            bail!(InvalidSnapshot)
+        }
+
+        let (body, checksum) = buf.split_at(buf.len() - 4);
+
+        if crc32(body) != Cursor::new(checksum).read_u32::<BigEndian>()? {
+            warn!("snapshot checksum mismatch");
+
+            bail!(InvalidSnapshot)
+        }
+
+        let mut r = Cursor::new(&body[SNAPSHOT_MAGIC.len() + 1..]);
+        let names = (0..r.read_u32::<BigEndian>()?)
+            .map(|_| {
+                let len = r.read_u16::<BigEndian>()? as usize;
+                let mut name = vec![0; len];
+
+                r.read_exact(&mut name)?;
+
+                String::from_utf8(name).map(Arc::from).map_err(|_| InvalidSnapshot.into())
+            })
+            .collect::<Result<Vec<Arc<str>>>>()?;
+
+        let mut tree = TopicTree::new();
+
+        for _ in 0..r.read_u32::<BigEndian>()? {
+            let levels = (0..r.read_u16::<BigEndian>()?)
+                .map(|_| {
+                    Ok(match r.read_u8()? {
+                        tag @ LEVEL_NORMAL | tag @ LEVEL_METADATA => {
+                            let idx = r.read_u32::<BigEndian>()? as usize;
+                            let name = names.get(idx).ok_or(InvalidSnapshot)?.clone();
+
+                            if tag == LEVEL_NORMAL {
+                                Level::Normal(name)
+                            } else {
+                                Level::Metadata(name)
+                            }
+                        }
+                        LEVEL_BLANK => Level::Blank,
+                        LEVEL_SINGLE_WILDCARD => Level::SingleWildcard,
+                        LEVEL_MULTI_WILDCARD => Level::MultiWildcard,
+                        _ => bail!(InvalidSnapshot),
+                    })
+                })
+                .collect::<Result<Vec<Level>>>()?;
+
+            let topic = Topic::from(levels);
+
+            if !topic.is_valid_filter() {
+                bail!(InvalidSnapshot)
+            }
+
+            let value = T::read_value(&mut r)?;
+
+            tree.insert(&topic, value);
+        }
+
+        if r.position() != r.get_ref().len() as u64 {
+            bail!(InvalidSnapshot)
+        }
+
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut tree = TopicTree::new();
+
+        tree.insert(&topic!("sport/tennis/+"), Some(String::from("client1")));
+        tree.insert(&topic!("sport/tennis/player1/#"), None);
+        tree.insert(&topic!("/finance"), Some(String::new()));
+        tree.insert(&topic!("+/+"), Some(String::from("client2")));
+        tree.insert(&topic!("$SYS/monitor/+"), Some(String::from("admin")));
+
+        let mut buf = Vec::new();
+
+        tree.save(&mut buf).unwrap();
+
+        assert_eq!(&buf[..5], b"MQTT\x01");
+
+        let loaded = TopicTree::<Option<String>>::load(&buf[..]).unwrap();
+
+        assert_eq!(loaded.len(), tree.len());
+        assert_eq!(loaded.stats(), tree.stats());
+
+        for (topic, value) in tree.entries() {
+            assert_eq!(loaded.get(topic), Some(value));
+        }
+
+        assert_eq!(loaded.match_topic(&topic!("sport/tennis/player1")),
+                   tree.match_topic(&topic!("sport/tennis/player1")));
+
+        let mut buf = Vec::new();
+
+        TopicTree::build(vec![]).save(&mut buf).unwrap();
+
+        assert!(TopicTree::<()>::load(&buf[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_snapshot() {
+        let tree = TopicTree::build(vec![topic!("sport/tennis/+"), topic!("sport/#")]);
+        let mut buf = Vec::new();
+
+        tree.save(&mut buf).unwrap();
+
+        assert_eq!(TopicTree::<()>::load(&buf[..]).unwrap().len(), 2);
+
+        assert!(TopicTree::<()>::load(&b""[..]).is_err());
+        assert!(TopicTree::<()>::load(&buf[..buf.len() - 1]).is_err());
+
+        let mut corrupted = buf.clone();
+
+        corrupted[10] ^= 0xFF;
+
+        assert!(TopicTree::<()>::load(&corrupted[..]).is_err());
+
+        let mut future = buf.clone();
+
+        future[4] = SNAPSHOT_VERSION + 1;
+
+        assert!(TopicTree::<()>::load(&future[..]).is_err());
+
+        let mut magic = buf.clone();
+
+        magic[0] = b'X';
+
+        assert!(TopicTree::<()>::load(&magic[..]).is_err());
+
+        // a valid checksum over a truncated body
+        let mut truncated = buf[..buf.len() - 6].to_vec();
+        let checksum = crc32(&truncated);
+
+        truncated.write_u32::<BigEndian>(checksum).unwrap();
+
+        assert!(TopicTree::<()>::load(&truncated[..]).is_err());
+    }
+}
@@ -1,25 +1,82 @@
 use std::io;
 use std::ops::{Deref, DerefMut, Div, DivAssign};
-use std::iter::{Iterator, IntoIterator};
+use std::iter::{Iterator, IntoIterator, FromIterator};
 use std::fmt::{self, Display, Formatter, Write};
 use std::str::FromStr;
 use std::convert::{AsRef, Into};
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::mem;
 
 use slab::Slab;
 
 use error::*;
 use error::ErrorKind::*;
 
+/// The maximum length in bytes of a UTF-8 encoded topic name or topic filter
+pub const MAX_TOPIC_LEN: usize = 65535;
+
 #[inline]
 fn is_metadata<T: AsRef<str>>(s: T) -> bool {
     s.as_ref().chars().nth(0) == Some('$')
 }
 
+#[inline]
+fn is_valid_level(s: &str) -> bool {
+    !s.contains(['+', '#', '/', '\0'])
+}
+
+fn validate_topic(s: &str, allow_wildcard: bool) -> bool {
+    if s.len() > MAX_TOPIC_LEN || s.contains('\0') {
+        return false;
+    }
+
+    let mut levels = s.split('/').enumerate().peekable();
+
+    while let Some((pos, level)) = levels.next() {
+        match level {
+            "+" | "#" if !allow_wildcard => return false,
+            "#" if levels.peek().is_some() => return false,
+            "+" | "#" => {}
+            _ if !is_valid_level(level) || (pos > 0 && is_metadata(level)) => return false,
+            _ => {}
+        }
+    }
+
+    true
+}
+
+/// Check whether the string is a valid topic name, which used in PUBLISH packet.
+///
+/// ```
+/// use mqtt::is_valid_topic_name;
+///
+/// assert!(is_valid_topic_name("sport/tennis/player1"));
+/// assert!(!is_valid_topic_name("sport/+/player1"));
+/// assert!(!is_valid_topic_name(""));
+/// ```
+pub fn is_valid_topic_name(s: &str) -> bool {
+    !s.is_empty() && validate_topic(s, false)
+}
+
+/// Check whether the string is a valid topic filter, which used in SUBSCRIBE packet.
+///
+/// ```
+/// use mqtt::is_valid_topic_filter;
+///
+/// assert!(is_valid_topic_filter("sport/+/player1"));
+/// assert!(is_valid_topic_filter("sport/#"));
+/// assert!(!is_valid_topic_filter("sport/#/player1"));
+/// assert!(!is_valid_topic_filter(""));
+/// ```
+pub fn is_valid_topic_filter(s: &str) -> bool {
+    !s.is_empty() && validate_topic(s, true)
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, Hash)]
 pub enum Level {
-    Normal(String),
-    Metadata(String), // $SYS
+    Normal(Arc<str>),
+    Metadata(Arc<str>), // $SYS
     Blank,
     SingleWildcard, // Single level wildcard +
     MultiWildcard, // Multi-level wildcard #
@@ -33,35 +90,31 @@ impl Level {
         Level::from_str(s.as_ref())
     }
 
-    pub fn normal<T: AsRef<str>>(s: T) -> Level {
-        if s.as_ref().contains(|c| c == '+' || c == '#') {
-            panic!("invalid normal level `{}` contains +|#", s.as_ref());
-        }
+    pub fn normal<T: AsRef<str>>(s: T) -> Result<Level> {
+        let s = s.as_ref();
 
-        if s.as_ref().chars().nth(0) == Some('$') {
-            panic!("invalid normal level `{}` starts with $", s.as_ref())
+        if is_metadata(s) || !is_valid_level(s) {
+            bail!(InvalidTopic)
         }
 
-        Level::Normal(String::from(s.as_ref()))
+        Ok(Level::Normal(s.into()))
     }
 
-    pub fn metadata<T: AsRef<str>>(s: T) -> Level {
-        if s.as_ref().contains(|c| c == '+' || c == '#') {
-            panic!("invalid metadata level `{}` contains +|#", s.as_ref());
-        }
+    pub fn metadata<T: AsRef<str>>(s: T) -> Result<Level> {
+        let s = s.as_ref();
 
-        if s.as_ref().chars().nth(0) != Some('$') {
-            panic!("invalid metadata level `{}` not starts with $", s.as_ref())
+        if !is_metadata(s) || !is_valid_level(s) {
+            bail!(InvalidTopic)
         }
 
-        Level::Metadata(String::from(s.as_ref()))
+        Ok(Level::Metadata(s.into()))
     }
 
     #[inline]
     pub fn value(&self) -> Option<&str> {
         match *self {
             Level::Normal(ref s) |
-            Level::Metadata(ref s) => Some(&s),
+            Level::Metadata(ref s) => Some(s),
             _ => None,
         }
     }
@@ -87,15 +140,41 @@ impl Level {
     #[inline]
     pub fn is_valid(&self) -> bool {
         match *self {
-            Level::Normal(ref s) => {
-                s.chars().nth(0) != Some('$') && !s.contains(|c| c == '+' || c == '#')
-            }
-            Level::Metadata(ref s) => {
-                s.chars().nth(0) == Some('$') && !s.contains(|c| c == '+' || c == '#')
-            }
+            Level::Normal(ref s) => !is_metadata(s) && is_valid_level(s),
+            Level::Metadata(ref s) => is_metadata(s) && is_valid_level(s),
             _ => true,
         }
     }
+
+    /// The length in bytes of the level when encoded as UTF-8
+    #[inline]
+    pub fn len(&self) -> usize {
+        match *self {
+            Level::Normal(ref s) | Level::Metadata(ref s) => s.len(),
+            Level::Blank => 0,
+            Level::SingleWildcard | Level::MultiWildcard => 1,
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether both levels share the same interned name
+    #[inline]
+    pub fn ptr_eq(&self, other: &Level) -> bool {
+        match (self, other) {
+            (&Level::Normal(ref lhs), &Level::Normal(ref rhs)) |
+            (&Level::Metadata(ref lhs), &Level::Metadata(ref rhs)) => Arc::ptr_eq(lhs, rhs),
+            _ => self == other,
+        }
+    }
+
+    #[inline]
+    pub fn is_wildcard(&self) -> bool {
+        matches!(*self, Level::SingleWildcard | Level::MultiWildcard)
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Hash)]
@@ -126,6 +205,30 @@ impl Topic {
             })
             .is_none()
     }
+
+    /// The length in bytes of the topic when encoded as UTF-8
+    pub fn len_bytes(&self) -> usize {
+        self.0.iter().map(|level| level.len()).sum::<usize>() + self.0.len().saturating_sub(1)
+    }
+
+    #[inline]
+    pub fn has_wildcard(&self) -> bool {
+        self.0.iter().any(|level| level.is_wildcard())
+    }
+
+    /// Whether the topic could be used as a topic name in PUBLISH packet.
+    #[inline]
+    pub fn is_valid_name(&self) -> bool {
+        self.is_valid_filter() && !self.has_wildcard()
+    }
+
+    /// Whether the topic could be used as a topic filter in SUBSCRIBE packet.
+    #[inline]
+    pub fn is_valid_filter(&self) -> bool {
+        let len = self.len_bytes();
+
+        len > 0 && len <= MAX_TOPIC_LEN && self.is_valid()
+    }
 }
 
 impl<'a> From<&'a [Level]> for Topic {
@@ -169,6 +272,96 @@ macro_rules! topic {
     ($s:expr) => ($s.parse::<Topic>().unwrap());
 }
 
+macro_rules! validated_topic {
+    ($name:ident, $check:ident, $is_valid:ident) => {
+        impl $name {
+            #[inline]
+            pub fn new<T: AsRef<str>>(s: T) -> Result<$name> {
+                s.as_ref().parse()
+            }
+
+            #[inline]
+            pub fn topic(&self) -> &Topic {
+                &self.0
+            }
+
+            #[inline]
+            pub fn into_topic(self) -> Topic {
+                self.0
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Error;
+
+            fn from_str(s: &str) -> Result<Self> {
+                if $check(s) {
+                    s.parse().map($name)
+                } else {
+                    bail!(InvalidTopic)
+                }
+            }
+        }
+
+        impl ::std::convert::TryFrom<Topic> for $name {
+            type Error = Error;
+
+            fn try_from(topic: Topic) -> Result<Self> {
+                if topic.$is_valid() {
+                    Ok($name(topic))
+                } else {
+                    bail!(InvalidTopic)
+                }
+            }
+        }
+
+        impl From<$name> for Topic {
+            fn from(t: $name) -> Topic {
+                t.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = Topic;
+
+            fn deref(&self) -> &Self::Target {
+                &self.0
+            }
+        }
+
+        impl AsRef<Topic> for $name {
+            fn as_ref(&self) -> &Topic {
+                &self.0
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+    }
+}
+
+/// Topic name used in PUBLISH packet, which must not contain wildcards.
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub struct TopicName(Topic);
+
+validated_topic!(TopicName, is_valid_topic_name, is_valid_name);
+
+/// Topic filter used in SUBSCRIBE and UNSUBSCRIBE packets, which may contain wildcards.
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub struct TopicFilter(Topic);
+
+validated_topic!(TopicFilter, is_valid_topic_filter, is_valid_filter);
+
+impl TopicFilter {
+    #[inline]
+    pub fn matches(&self, name: &TopicName) -> bool {
+        name.match_topic(&self.0)
+    }
+}
+
 pub trait MatchLevel {
     fn match_level(&self, level: &Level) -> bool;
 }
@@ -199,8 +392,8 @@ impl MatchLevel for Level {
 impl<T: AsRef<str>> MatchLevel for T {
     fn match_level(&self, level: &Level) -> bool {
         match *level {
-            Level::Normal(ref lhs) => !is_metadata(self) && lhs == self.as_ref(),
-            Level::Metadata(ref lhs) => is_metadata(self) && lhs == self.as_ref(),
+            Level::Normal(ref lhs) => !is_metadata(self) && **lhs == *self.as_ref(),
+            Level::Metadata(ref lhs) => is_metadata(self) && **lhs == *self.as_ref(),
             Level::Blank => self.as_ref().is_empty(),
             Level::SingleWildcard | Level::MultiWildcard => !is_metadata(self),
         }
@@ -250,6 +443,96 @@ impl<T: AsRef<str>> MatchTopic for T {
     }
 }
 
+#[inline]
+fn match_wildcard(level: Option<&Level>) -> bool {
+    !level.is_some_and(Level::is_metadata)
+}
+
+fn covers_levels(lhs: &[Level], rhs: &[Level]) -> bool {
+    match (lhs.split_first(), rhs.split_first()) {
+        (Some((&Level::MultiWildcard, _)), _) => match_wildcard(rhs.first()),
+        (Some((&Level::SingleWildcard, lhs)), Some((level, rhs))) => {
+            *level != Level::MultiWildcard && !level.is_metadata() && covers_levels(lhs, rhs)
+        }
+        (Some((lhs_level, lhs)), Some((rhs_level, rhs))) => {
+            lhs_level == rhs_level && covers_levels(lhs, rhs)
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn intersect_levels(lhs: &[Level], rhs: &[Level], levels: &mut Vec<Level>) -> bool {
+    match (lhs.split_first(), rhs.split_first()) {
+        (Some((&Level::MultiWildcard, _)), _) if match_wildcard(rhs.first()) => {
+            levels.extend_from_slice(rhs);
+            true
+        }
+        (Some((&Level::MultiWildcard, _)), _) => false,
+        (_, Some((&Level::MultiWildcard, _))) => intersect_levels(rhs, lhs, levels),
+        (Some((lhs_level, lhs)), Some((rhs_level, rhs))) => {
+            let level = match (lhs_level, rhs_level) {
+                (&Level::SingleWildcard, level) | (level, &Level::SingleWildcard) => {
+                    if level.is_metadata() {
+                        return false;
+                    }
+
+                    level
+                }
+                (lhs_level, rhs_level) if lhs_level == rhs_level => lhs_level,
+                _ => return false,
+            };
+
+            levels.push(level.clone());
+
+            intersect_levels(lhs, rhs, levels)
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+impl Topic {
+    /// Whether every topic matched by `other` filter is also matched by this filter
+    ///
+    /// ```
+    /// use mqtt::Topic;
+    ///
+    /// let filter: Topic = "sport/#".parse().unwrap();
+    ///
+    /// assert!(filter.covers(&"sport/+/player1".parse().unwrap()));
+    /// assert!(!filter.covers(&"+/tennis".parse().unwrap()));
+    /// ```
+    pub fn covers(&self, other: &Topic) -> bool {
+        covers_levels(&self.0, &other.0)
+    }
+
+    /// Whether there is any topic could be matched by both filters
+    pub fn overlaps(&self, other: &Topic) -> bool {
+        self.intersection(other).is_some()
+    }
+
+    /// The filter which matches exactly the topics matched by both filters
+    ///
+    /// ```
+    /// use mqtt::Topic;
+    ///
+    /// let lhs: Topic = "sport/+/player1".parse().unwrap();
+    /// let rhs: Topic = "+/tennis/#".parse().unwrap();
+    ///
+    /// assert_eq!(lhs.intersection(&rhs), Some("sport/tennis/player1".parse().unwrap()));
+    /// ```
+    pub fn intersection(&self, other: &Topic) -> Option<Topic> {
+        let mut levels = Vec::with_capacity(self.0.len().max(other.0.len()));
+
+        if intersect_levels(&self.0, &other.0, &mut levels) {
+            Some(Topic(levels))
+        } else {
+            None
+        }
+    }
+}
+
 impl FromStr for Level {
     type Err = Error;
 
@@ -260,12 +543,12 @@ impl FromStr for Level {
             "#" => Ok(Level::MultiWildcard),
             "" => Ok(Level::Blank),
             _ => {
-                if s.contains(|c| c == '+' || c == '#') {
+                if !is_valid_level(s) {
                     bail!(InvalidTopic)
                 } else if is_metadata(s) {
-                    Ok(Level::Metadata(String::from(s)))
+                    Ok(Level::Metadata(s.into()))
                 } else {
-                    Ok(Level::Normal(String::from(s)))
+                    Ok(Level::Normal(s.into()))
                 }
             }
         }
@@ -277,6 +560,10 @@ impl FromStr for Topic {
 
     #[inline]
     fn from_str(s: &str) -> Result<Self> {
+        if s.len() > MAX_TOPIC_LEN {
+            bail!(InvalidTopic)
+        }
+
         s.split('/')
             .map(|level| Level::from_str(level))
             .collect::<Result<Vec<_>>>()
@@ -294,8 +581,7 @@ impl FromStr for Topic {
 impl Display for Level {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match *self {
-            Level::Normal(ref s) => f.write_str(s.as_str()),
-            Level::Metadata(ref s) => f.write_str(s.as_str()),
+            Level::Normal(ref s) | Level::Metadata(ref s) => f.write_str(s),
             Level::Blank => Ok(()),
             Level::SingleWildcard => f.write_char('+'),
             Level::MultiWildcard => f.write_char('#'),
@@ -324,8 +610,7 @@ impl Display for Topic {
 pub trait WriteTopicExt: io::Write {
     fn write_level(&mut self, level: &Level) -> io::Result<usize> {
         match *level {
-            Level::Normal(ref s) => self.write(s.as_str().as_bytes()),
-            Level::Metadata(ref s) => self.write(s.as_str().as_bytes()),
+            Level::Normal(ref s) | Level::Metadata(ref s) => self.write(s.as_bytes()),
             Level::Blank => Ok(0),
             Level::SingleWildcard => self.write(b"+"),
             Level::MultiWildcard => self.write(b"#"),
@@ -402,6 +687,107 @@ impl DivAssign<Topic> for Topic {
     }
 }
 
+/// Pool of level names, shared by the topics to avoid duplicated allocations.
+///
+/// The pool counts the uses of every name, each `intern` counts one until it is
+/// given back with `release`, and a name is dropped once it has no use left.
+///
+/// ```
+/// use mqtt::{Interner, Topic};
+///
+/// let mut interner = Interner::new();
+///
+/// let t1 = interner.intern_topic(&"site/1/sensor/temp".parse().unwrap());
+/// let t2 = interner.intern_topic(&"site/2/sensor/temp".parse().unwrap());
+///
+/// assert_eq!(interner.len(), 5);
+/// assert!(t1[0].ptr_eq(&t2[0]));
+///
+/// interner.release_topic(&t2);
+///
+/// assert_eq!(interner.len(), 4);
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct Interner(HashMap<Arc<str>, usize>);
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner::default()
+    }
+
+    /// The number of distinct level names in the pool
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the shared name, counting a use of it.
+    pub fn intern<T: AsRef<str>>(&mut self, s: T) -> Arc<str> {
+        if let Some(uses) = self.0.get_mut(s.as_ref()) {
+            *uses += 1;
+        }
+
+        if let Some((name, _)) = self.0.get_key_value(s.as_ref()) {
+            return name.clone();
+        }
+
+        let name: Arc<str> = Arc::from(s.as_ref());
+
+        self.0.insert(name.clone(), 1);
+
+        name
+    }
+
+    /// Give back a use of the name, dropping it once it has no use left.
+    pub fn release<T: AsRef<str>>(&mut self, s: T) {
+        let unused = match self.0.get_mut(s.as_ref()) {
+            Some(uses) => {
+                *uses -= 1;
+                *uses == 0
+            }
+            None => false,
+        };
+
+        if unused {
+            self.0.remove(s.as_ref());
+        }
+    }
+
+    pub fn intern_level(&mut self, level: &Level) -> Level {
+        match *level {
+            Level::Normal(ref s) => Level::Normal(self.intern(s)),
+            Level::Metadata(ref s) => Level::Metadata(self.intern(s)),
+            _ => level.clone(),
+        }
+    }
+
+    pub fn release_level(&mut self, level: &Level) {
+        if let Level::Normal(ref s) | Level::Metadata(ref s) = *level {
+            self.release(s)
+        }
+    }
+
+    pub fn intern_topic(&mut self, topic: &Topic) -> Topic {
+        Topic(topic.0.iter().map(|level| self.intern_level(level)).collect())
+    }
+
+    pub fn release_topic(&mut self, topic: &Topic) {
+        for level in topic.0.iter() {
+            self.release_level(level)
+        }
+    }
+
+    /// Parse a topic with the level names from the pool
+    pub fn parse_topic<T: AsRef<str>>(&mut self, s: T) -> Result<Topic> {
+        s.as_ref().parse().map(|topic| self.intern_topic(&topic))
+    }
+}
+
 type TopicIdx = usize;
 type StateIdx = usize;
 
@@ -413,25 +799,79 @@ struct State {
     multi_wildcard: Option<TopicIdx>,
 }
 
+impl State {
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.next.is_empty() && self.out.is_none() && self.single_wildcard.is_none() &&
+            self.multi_wildcard.is_none()
+    }
+}
+
+/// Statistics of a `TopicTree`
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct Stats {
+    /// the number of stored topic filters
+    pub topics: usize,
+    /// the number of states, including the root state
+    pub states: usize,
+    /// the maximum number of levels from the root state
+    pub max_depth: usize,
+    /// the number of states with a single level wildcard `+` transition
+    pub single_wildcards: usize,
+    /// the number of states with a multi-level wildcard `#` filter
+    pub multi_wildcards: usize,
+    /// the number of distinct interned level names
+    pub names: usize,
+    /// approximate memory used by the tree in bytes
+    pub memory: usize,
+}
+
+/// An iterator over the topic filters of a `TopicTree`
+pub struct Iter<'a, T: 'a>(::slab::Iter<'a, (Topic, T), TopicIdx>);
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a Topic;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(topic, _)| topic)
+    }
+}
+
+/// An iterator over the topic filters and their values of a `TopicTree`
+pub struct Entries<'a, T: 'a>(::slab::Iter<'a, (Topic, T), TopicIdx>);
+
+impl<'a, T> Iterator for Entries<'a, T> {
+    type Item = (&'a Topic, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(topic, value)| (topic, value))
+    }
+}
+
+/// Topic filters with attached values, which could match a topic to all its filters.
 #[derive(Debug)]
-pub struct TopicTree {
-    topics: Slab<Topic, TopicIdx>,
+pub struct TopicTree<T = ()> {
+    topics: Slab<(Topic, T), TopicIdx>,
     states: Slab<State, StateIdx>,
     root: StateIdx,
+    interner: Interner,
 }
 
-impl TopicTree {
-    pub fn new() -> TopicTree {
+impl<T> Default for TopicTree<T> {
+    fn default() -> Self {
         let mut states = Slab::with_capacity(64);
         let root = states.insert(Default::default()).ok().unwrap();
 
         TopicTree {
             topics: Slab::with_capacity(64),
-            states: states,
-            root: root,
+            states,
+            root,
+            interner: Interner::new(),
         }
     }
+}
 
+impl TopicTree {
     pub fn build<I: IntoIterator<Item = Topic>>(topics: I) -> TopicTree {
         let mut tree = TopicTree::new();
 
@@ -441,18 +881,154 @@ impl TopicTree {
 
         tree
     }
+}
 
-    pub fn add(&mut self, topic: &Topic) {
-        let mut cur_state = self.root;
-        let topic_idx = self.topics.iter().position(|ref t| **t == *topic).unwrap_or_else(|| {
-            if !self.topics.has_available() {
-                let cap = self.topics.capacity();
+impl<T: Default> TopicTree<T> {
+    /// Add the topic filter with a default value if not exists, returns the attached value.
+    pub fn add(&mut self, topic: &Topic) -> &mut T {
+        let topic_idx = match self.find(topic) {
+            Some(idx) => idx,
+            None => self.add_topic(topic, T::default()),
+        };
+
+        &mut self.topics[topic_idx].1
+    }
+}
+
+impl<T> TopicTree<T> {
+    pub fn new() -> TopicTree<T> {
+        TopicTree::default()
+    }
+
+    /// The number of topic filters in the tree
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.topics.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.topics.is_empty()
+    }
+
+    /// An iterator visiting all topic filters in the tree
+    #[inline]
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter(self.topics.iter())
+    }
+
+    /// An iterator visiting all topic filters and their values in the tree
+    #[inline]
+    pub fn entries(&self) -> Entries<'_, T> {
+        Entries(self.topics.iter())
+    }
+
+    /// Whether the topic filter has been added to the tree
+    #[inline]
+    pub fn contains(&self, topic: &Topic) -> bool {
+        self.find(topic).is_some()
+    }
+
+    /// The value attached to the topic filter
+    #[inline]
+    pub fn get(&self, topic: &Topic) -> Option<&T> {
+        self.find(topic).map(|idx| &self.topics[idx].1)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, topic: &Topic) -> Option<&mut T> {
+        self.find(topic).map(move |idx| &mut self.topics[idx].1)
+    }
+
+    fn find(&self, topic: &Topic) -> Option<TopicIdx> {
+        let mut state = &self.states[self.root];
+
+        for level in topic.0.iter() {
+            let next_state = match *level {
+                Level::SingleWildcard => state.single_wildcard,
+                Level::MultiWildcard => return state.multi_wildcard,
+                _ => state.next.get(level).cloned(),
+            };
+
+            state = &self.states[next_state?];
+        }
 
-                self.topics.reserve_exact(cap);
+        state.out
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            topics: self.topics.len(),
+            states: self.states.len(),
+            max_depth: self.depth(self.root),
+            names: self.interner.len(),
+            ..Default::default()
+        };
+
+        stats.memory = mem::size_of::<Self>() +
+            self.topics.capacity() * mem::size_of::<(Topic, T)>() +
+            self.states.capacity() * mem::size_of::<State>();
+
+        for (topic, _) in self.topics.iter() {
+            stats.memory += topic.0.capacity() * mem::size_of::<Level>();
+        }
+
+        for state in self.states.iter() {
+            if state.single_wildcard.is_some() {
+                stats.single_wildcards += 1;
+            }
+
+            if state.multi_wildcard.is_some() {
+                stats.multi_wildcards += 1;
             }
 
-            self.topics.vacant_entry().map(|entry| entry.insert(topic.clone()).index()).unwrap()
-        });
+            stats.memory += state.next.capacity() *
+                (mem::size_of::<Level>() + mem::size_of::<StateIdx>() + 1);
+        }
+
+        for name in self.interner.0.keys() {
+            stats.memory += mem::size_of::<Arc<str>>() + 2 * mem::size_of::<usize>() + name.len();
+        }
+
+        stats
+    }
+
+    fn depth(&self, state_idx: StateIdx) -> usize {
+        let state = &self.states[state_idx];
+
+        state
+            .next
+            .values()
+            .chain(state.single_wildcard.iter())
+            .map(|&next_state| self.depth(next_state) + 1)
+            .chain(state.multi_wildcard.map(|_| 1))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Insert the topic filter with the value, returns the old value if the filter exists.
+    pub fn insert(&mut self, topic: &Topic, value: T) -> Option<T> {
+        match self.find(topic) {
+            Some(idx) => Some(mem::replace(&mut self.topics[idx].1, value)),
+            None => {
+                self.add_topic(topic, value);
+
+                None
+            }
+        }
+    }
+
+    fn add_topic(&mut self, topic: &Topic, value: T) -> TopicIdx {
+        let mut cur_state = self.root;
+        let topic = self.interner.intern_topic(topic);
+
+        if !self.topics.has_available() {
+            let cap = self.topics.capacity();
+
+            self.topics.reserve_exact(cap);
+        }
+
+        let topic_idx = self.topics.insert((topic.clone(), value)).ok().unwrap();
 
         for level in topic.0.iter() {
             match *level {
@@ -463,8 +1039,10 @@ impl TopicTree {
                         Some(&next_state) => cur_state = next_state,
                         None => {
                             let next_state = self.add_state();
+                            // the key shares the name of the interner, stored once in the tree
+                            let key = self.interner.intern_level(level);
 
-                            self.states[cur_state].next.insert(level.clone(), next_state);
+                            self.states[cur_state].next.insert(key, next_state);
 
                             cur_state = next_state;
                         }
@@ -483,16 +1061,16 @@ impl TopicTree {
                     }
                 }
                 Level::MultiWildcard => {
-                    if self.states[cur_state].multi_wildcard.is_none() {
-                        self.states[cur_state].multi_wildcard = Some(topic_idx);
-                    }
+                    self.states[cur_state].multi_wildcard = Some(topic_idx);
 
-                    return;
+                    return topic_idx;
                 }
             }
         }
 
         self.states[cur_state].out = Some(topic_idx);
+
+        topic_idx
     }
 
     #[inline]
@@ -509,6 +1087,53 @@ impl TopicTree {
             .unwrap()
     }
 
+    /// Remove the topic filter, returns the attached value if the filter exists.
+    pub fn remove(&mut self, topic: &Topic) -> Option<T> {
+        let mut path = Vec::with_capacity(topic.len());
+        let mut cur_state = self.root;
+
+        for level in topic.0.iter() {
+            let next_state = match *level {
+                Level::SingleWildcard => self.states[cur_state].single_wildcard,
+                Level::MultiWildcard => break,
+                _ => self.states[cur_state].next.get(level).cloned(),
+            };
+
+            path.push((cur_state, level));
+
+            cur_state = next_state?;
+        }
+
+        let topic_idx = if topic.last() == Some(&Level::MultiWildcard) {
+            self.states[cur_state].multi_wildcard.take()
+        } else {
+            self.states[cur_state].out.take()
+        }?;
+
+        while let Some((parent, level)) = path.pop() {
+            if !self.states[cur_state].is_empty() {
+                break;
+            }
+
+            self.states.remove(cur_state);
+
+            if *level == Level::SingleWildcard {
+                self.states[parent].single_wildcard = None;
+            } else {
+                self.states[parent].next.remove(level);
+                self.interner.release_level(level);
+            }
+
+            cur_state = parent;
+        }
+
+        let (topic, value) = self.topics.remove(topic_idx).unwrap();
+
+        self.interner.release_topic(&topic);
+
+        Some(value)
+    }
+
     pub fn match_topic(&self, topic: &Topic) -> Option<Vec<&Topic>> {
         let mut topics = Vec::with_capacity(16);
 
@@ -517,10 +1142,26 @@ impl TopicTree {
         if topics.is_empty() {
             None
         } else {
-            Some(topics.iter().map(|&idx| &self.topics[idx]).collect())
+            Some(topics.iter().map(|&idx| &self.topics[idx].0).collect())
         }
     }
 
+    /// Match the topic to all its filters and their values
+    pub fn matches(&self, topic: &Topic) -> Vec<(&Topic, &T)> {
+        let mut topics = Vec::with_capacity(16);
+
+        self.match_state(&self.states[self.root], topic.0.as_slice(), &mut topics);
+
+        topics
+            .iter()
+            .map(|&idx| {
+                let (ref topic, ref value) = self.topics[idx];
+
+                (topic, value)
+            })
+            .collect()
+    }
+
     fn match_state(&self, state: &State, levels: &[Level], topics: &mut Vec<TopicIdx>) {
         if let Some(topic) = state.multi_wildcard {
             if let Some(&Level::Metadata(_)) = levels.first() {
@@ -551,73 +1192,152 @@ impl TopicTree {
     }
 }
 
+impl<T> FromIterator<(Topic, T)> for TopicTree<T> {
+    fn from_iter<I: IntoIterator<Item = (Topic, T)>>(iter: I) -> Self {
+        let mut tree = TopicTree::new();
+
+        for (topic, value) in iter {
+            tree.insert(&topic, value);
+        }
+
+        tree
+    }
+}
+
+impl<'a, T> IntoIterator for &'a TopicTree<T> {
+    type Item = &'a Topic;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate env_logger;
 
+    use std::convert::TryFrom;
+
     use super::*;
 
     #[test]
     fn test_level() {
-        assert!(Level::normal("sport").is_normal());
-        assert!(Level::metadata("$SYS").is_metadata());
+        assert!(Level::normal("sport").unwrap().is_normal());
+        assert!(Level::metadata("$SYS").unwrap().is_metadata());
+
+        assert_eq!(Level::normal("sport").unwrap().value(), Some("sport"));
+        assert_eq!(Level::metadata("$SYS").unwrap().value(), Some("$SYS"));
 
-        assert_eq!(Level::normal("sport").value(), Some("sport"));
-        assert_eq!(Level::metadata("$SYS").value(), Some("$SYS"));
+        assert_eq!(Level::normal("sport").unwrap(), "sport".parse().unwrap());
+        assert_eq!(Level::metadata("$SYS").unwrap(), "$SYS".parse().unwrap());
 
-        assert_eq!(Level::normal("sport"), "sport".parse().unwrap());
-        assert_eq!(Level::metadata("$SYS"), "$SYS".parse().unwrap());
+        assert!(Level::Normal(Arc::from("sport")).is_valid());
+        assert!(Level::Metadata(Arc::from("$SYS")).is_valid());
 
-        assert!(Level::Normal(String::from("sport")).is_valid());
-        assert!(Level::Metadata(String::from("$SYS")).is_valid());
+        assert!(!Level::Normal(Arc::from("$sport")).is_valid());
+        assert!(!Level::Metadata(Arc::from("SYS")).is_valid());
 
-        assert!(!Level::Normal(String::from("$sport")).is_valid());
-        assert!(!Level::Metadata(String::from("SYS")).is_valid());
+        assert!(!Level::Normal(Arc::from("sport#")).is_valid());
+        assert!(!Level::Metadata(Arc::from("SYS+")).is_valid());
+        assert!(!Level::Normal(Arc::from("sport/tennis")).is_valid());
+        assert!(!Level::Normal(Arc::from("sport\0")).is_valid());
 
-        assert!(!Level::Normal(String::from("sport#")).is_valid());
-        assert!(!Level::Metadata(String::from("SYS+")).is_valid());
+        assert!(Level::normal("$SYS").is_err());
+        assert!(Level::normal("sport+").is_err());
+        assert!(Level::metadata("SYS").is_err());
+        assert!(Level::metadata("$SYS/#").is_err());
+    }
+
+    #[test]
+    fn test_topic_name() {
+        let name = TopicName::new("sport/tennis/player1").unwrap();
+
+        assert_eq!(name.topic(), &topic!("sport/tennis/player1"));
+        assert_eq!(name.to_string(), "sport/tennis/player1");
+
+        assert!(TopicName::new("/").is_ok());
+        assert!(TopicName::new("$SYS/monitor").is_ok());
+
+        assert!(TopicName::new("").is_err());
+        assert!(TopicName::new("sport/+/player1").is_err());
+        assert!(TopicName::new("sport/#").is_err());
+        assert!(TopicName::new("sport/\0").is_err());
+        assert!(TopicName::new(String::from_utf8(vec![b'a'; MAX_TOPIC_LEN]).unwrap()).is_ok());
+        assert!(TopicName::new(String::from_utf8(vec![b'a'; MAX_TOPIC_LEN + 1]).unwrap()).is_err());
+
+        assert!(TopicName::try_from(topic!("sport/tennis")).is_ok());
+        assert!(TopicName::try_from(topic!("sport/+")).is_err());
+        assert!(TopicName::try_from(topic!("")).is_err());
+    }
+
+    #[test]
+    fn test_topic_filter() {
+        let filter = TopicFilter::new("sport/+/player1").unwrap();
+
+        assert_eq!(filter.topic(), &topic!("sport/+/player1"));
+        assert!(filter.matches(&TopicName::new("sport/tennis/player1").unwrap()));
+        assert!(!filter.matches(&TopicName::new("sport/tennis/player2").unwrap()));
+
+        assert!(TopicFilter::new("").is_err());
+        assert!(TopicFilter::new("#").is_ok());
+        assert!(TopicFilter::new("$SYS/#").is_ok());
+
+        assert!(TopicFilter::new("sport/#/player1").is_err());
+        assert!(TopicFilter::new("sport+").is_err());
+        assert!(TopicFilter::new("sport/$SYS").is_err());
+        assert!(TopicFilter::new("sport/\0").is_err());
+
+        assert!(TopicFilter::try_from(topic!("sport/+")).is_ok());
+        assert!(TopicFilter::try_from(Topic(vec![])).is_err());
+        assert!(TopicFilter::try_from(topic!("")).is_err());
+        assert!(TopicFilter::try_from(Topic(vec![Level::MultiWildcard, Level::Blank])).is_err());
     }
 
     #[test]
     fn test_valid_topic() {
-        assert!(Topic(vec![Level::normal("sport"),
-                           Level::normal("tennis"),
-                           Level::normal("player1")])
+        assert!(Topic(vec![Level::normal("sport").unwrap(),
+                           Level::normal("tennis").unwrap(),
+                           Level::normal("player1").unwrap()])
             .is_valid());
 
-        assert!(Topic(vec![Level::normal("sport"), Level::normal("tennis"), Level::MultiWildcard])
+        assert!(Topic(vec![Level::normal("sport").unwrap(),
+                           Level::normal("tennis").unwrap(),
+                           Level::MultiWildcard])
             .is_valid());
-        assert!(Topic(vec![Level::metadata("$SYS"),
-                           Level::normal("tennis"),
+        assert!(Topic(vec![Level::metadata("$SYS").unwrap(),
+                           Level::normal("tennis").unwrap(),
                            Level::MultiWildcard])
             .is_valid());
 
-        assert!(Topic(vec![Level::normal("sport"),
+        assert!(Topic(vec![Level::normal("sport").unwrap(),
                            Level::SingleWildcard,
-                           Level::normal("player1")])
+                           Level::normal("player1").unwrap()])
             .is_valid());
 
-        assert!(!Topic(vec![Level::normal("sport"),
+        assert!(!Topic(vec![Level::normal("sport").unwrap(),
                             Level::MultiWildcard,
-                            Level::normal("player1")])
+                            Level::normal("player1").unwrap()])
             .is_valid());
-        assert!(!Topic(vec![Level::normal("sport"),
-                            Level::metadata("$SYS"),
-                            Level::normal("player1")])
+        assert!(!Topic(vec![Level::normal("sport").unwrap(),
+                            Level::metadata("$SYS").unwrap(),
+                            Level::normal("player1").unwrap()])
             .is_valid());
     }
 
     #[test]
     fn test_parse_topic() {
         assert_eq!(topic!("sport/tennis/player1"),
-                   vec![Level::normal("sport"), Level::normal("tennis"), Level::normal("player1")]
+                   vec![Level::normal("sport").unwrap(),
+                        Level::normal("tennis").unwrap(),
+                        Level::normal("player1").unwrap()]
                        .into());
 
         assert_eq!(topic!(""), Topic(vec![Level::Blank]));
         assert_eq!(topic!("/finance"),
-                   vec![Level::Blank, Level::normal("finance")].into());
+                   vec![Level::Blank, Level::normal("finance").unwrap()].into());
 
-        assert_eq!(topic!("$SYS"), vec![Level::metadata("$SYS")].into());
+        assert_eq!(topic!("$SYS"), vec![Level::metadata("$SYS").unwrap()].into());
 
         assert!("sport/$SYS".parse::<Topic>().is_err());
     }
@@ -625,7 +1345,9 @@ mod tests {
     #[test]
     fn test_multi_wildcard_topic() {
         assert_eq!(topic!("sport/tennis/#"),
-                   vec![Level::normal("sport"), Level::normal("tennis"), Level::MultiWildcard]
+                   vec![Level::normal("sport").unwrap(),
+                        Level::normal("tennis").unwrap(),
+                        Level::MultiWildcard]
                        .into());
 
         assert_eq!(topic!("#"), vec![Level::MultiWildcard].into());
@@ -639,11 +1361,15 @@ mod tests {
         assert_eq!(topic!("+"), vec![Level::SingleWildcard].into());
 
         assert_eq!(topic!("+/tennis/#"),
-                   vec![Level::SingleWildcard, Level::normal("tennis"), Level::MultiWildcard]
+                   vec![Level::SingleWildcard,
+                        Level::normal("tennis").unwrap(),
+                        Level::MultiWildcard]
                        .into());
 
         assert_eq!(topic!("sport/+/player1"),
-                   vec![Level::normal("sport"), Level::SingleWildcard, Level::normal("player1")]
+                   vec![Level::normal("sport").unwrap(),
+                        Level::SingleWildcard,
+                        Level::normal("player1").unwrap()]
                        .into());
 
         assert!("sport+".parse::<Topic>().is_err());
@@ -652,7 +1378,10 @@ mod tests {
     #[test]
     fn test_write_topic() {
         let mut v = vec![];
-        let t = vec![Level::SingleWildcard, Level::normal("tennis"), Level::MultiWildcard].into();
+        let t = vec![Level::SingleWildcard,
+                     Level::normal("tennis").unwrap(),
+                     Level::MultiWildcard]
+            .into();
 
         assert_eq!(v.write_topic(&t).unwrap(), 10);
         assert_eq!(v, b"+/tennis/#");
@@ -663,8 +1392,8 @@ mod tests {
 
     #[test]
     fn test_match_topic() {
-        assert!("test".match_level(&Level::normal("test")));
-        assert!("$SYS".match_level(&Level::metadata("$SYS")));
+        assert!("test".match_level(&Level::normal("test").unwrap()));
+        assert!("$SYS".match_level(&Level::metadata("$SYS").unwrap()));
 
         let t = "sport/tennis/player1/#".parse().unwrap();
 
@@ -695,20 +1424,67 @@ mod tests {
         assert!("$SYS/monitor/Clients".match_topic(&"$SYS/monitor/+".parse().unwrap()));
     }
 
+    #[test]
+    fn test_covers_topic() {
+        assert!(topic!("#").covers(&topic!("sport/tennis/player1")));
+        assert!(topic!("#").covers(&topic!("+/+")));
+        assert!(topic!("#").covers(&topic!("#")));
+        assert!(topic!("sport/#").covers(&topic!("sport")));
+        assert!(topic!("sport/#").covers(&topic!("sport/+/player1")));
+        assert!(topic!("sport/#").covers(&topic!("sport/tennis/#")));
+        assert!(topic!("sport/+").covers(&topic!("sport/tennis")));
+        assert!(topic!("sport/+").covers(&topic!("sport/")));
+        assert!(topic!("+/+").covers(&topic!("/finance")));
+        assert!(topic!("sport/tennis").covers(&topic!("sport/tennis")));
+        assert!(topic!("$SYS/#").covers(&topic!("$SYS/monitor/+")));
+
+        assert!(!topic!("sport/+").covers(&topic!("sport/#")));
+        assert!(!topic!("sport/+").covers(&topic!("sport")));
+        assert!(!topic!("sport/tennis").covers(&topic!("sport/+")));
+        assert!(!topic!("sport/tennis").covers(&topic!("sport/#")));
+        assert!(!topic!("sport/tennis/#").covers(&topic!("sport/#")));
+        assert!(!topic!("sport/tennis").covers(&topic!("sport/tennis/player1")));
+        assert!(!topic!("#").covers(&topic!("$SYS/#")));
+        assert!(!topic!("+/monitor").covers(&topic!("$SYS/monitor")));
+    }
+
+    #[test]
+    fn test_intersect_topic() {
+        assert_eq!(topic!("sport/#").intersection(&topic!("sport/tennis/+")),
+                   Some(topic!("sport/tennis/+")));
+        assert_eq!(topic!("sport/+/player1").intersection(&topic!("+/tennis/#")),
+                   Some(topic!("sport/tennis/player1")));
+        assert_eq!(topic!("sport/#").intersection(&topic!("+/#")), Some(topic!("sport/#")));
+        assert_eq!(topic!("sport/#").intersection(&topic!("sport")), Some(topic!("sport")));
+        assert_eq!(topic!("+/+").intersection(&topic!("/finance")), Some(topic!("/finance")));
+        assert_eq!(topic!("#").intersection(&topic!("$SYS/monitor")), None);
+        assert_eq!(topic!("+/monitor").intersection(&topic!("$SYS/+")), None);
+        assert_eq!(topic!("$SYS/#").intersection(&topic!("$SYS/monitor/+")),
+                   Some(topic!("$SYS/monitor/+")));
+        assert_eq!(topic!("sport/tennis").intersection(&topic!("sport/golf")), None);
+        assert_eq!(topic!("sport/+").intersection(&topic!("sport/tennis/+")), None);
+
+        assert!(topic!("sport/+").overlaps(&topic!("+/tennis")));
+        assert!(topic!("+/+").overlaps(&topic!("/+")));
+        assert!(!topic!("sport/+").overlaps(&topic!("sport")));
+        assert!(!topic!("+/#").overlaps(&topic!("$SYS/monitor")));
+    }
+
     #[test]
     fn test_operators() {
-        assert_eq!(Level::normal("sport") / Level::normal("tennis") / Level::normal("player1"),
+        assert_eq!(Level::normal("sport").unwrap() / Level::normal("tennis").unwrap() /
+                   Level::normal("player1").unwrap(),
                    "sport/tennis/player1".parse().unwrap());
-        assert_eq!(topic!("sport/tennis") / Level::normal("player1"),
+        assert_eq!(topic!("sport/tennis") / Level::normal("player1").unwrap(),
                    "sport/tennis/player1".parse().unwrap());
-        assert_eq!(Level::normal("sport") / topic!("tennis/player1"),
+        assert_eq!(Level::normal("sport").unwrap() / topic!("tennis/player1"),
                    "sport/tennis/player1".parse().unwrap());
         assert_eq!(topic!("sport/tennis") / topic!("player1/ranking"),
                    "sport/tennis/player1/ranking".parse().unwrap());
 
         let mut t = topic!("sport/tennis");
 
-        t /= Level::normal("player1");
+        t /= Level::normal("player1").unwrap();
 
         assert_eq!(t, "sport/tennis/player1".parse().unwrap());
 
@@ -717,6 +1493,64 @@ mod tests {
         assert_eq!(t, "sport/tennis/player1/ranking".parse().unwrap());
     }
 
+    #[test]
+    fn test_interner() {
+        let mut interner = Interner::new();
+
+        let t1 = interner.parse_topic("site/1/sensor/temp").unwrap();
+        let t2 = interner.parse_topic("site/2/sensor/temp").unwrap();
+        let t3 = interner.parse_topic("$SYS/+/sensor/#").unwrap();
+
+        assert_eq!(t1, topic!("site/1/sensor/temp"));
+        assert_eq!(t3, topic!("$SYS/+/sensor/#"));
+        assert_eq!(interner.len(), 6);
+
+        assert!(t1[0].ptr_eq(&t2[0]));
+        assert!(!t1[1].ptr_eq(&t2[1]));
+        assert!(t1[2].ptr_eq(&t3[2]));
+        assert!(!t1[2].ptr_eq(&topic!("site/1/sensor/temp")[2]));
+
+        interner.release_topic(&t2);
+        interner.release_topic(&t3);
+
+        assert_eq!(interner.len(), 4);
+
+        // a name is only dropped with its last use
+        let name = interner.intern("sensor");
+
+        interner.release_topic(&t1);
+
+        assert_eq!(interner.len(), 1);
+
+        interner.release(&name);
+
+        assert!(interner.is_empty());
+
+        let t1 = interner.intern_topic(&t1);
+
+        let t4 = interner.intern_topic(&topic!("site/1/sensor"));
+
+        assert!(t1[1].ptr_eq(&t4[1]));
+    }
+
+    #[test]
+    fn test_topic_tree_interned_keys() {
+        let tree = TopicTree::build(vec![topic!("site/1/sensor/temp"),
+                                         topic!("site/2/sensor/temp"),
+                                         topic!("$SYS/+/sensor/#")]);
+
+        assert_eq!(tree.stats().names, 6);
+
+        // every transition shares the name stored once in the interner
+        for state in tree.states.iter() {
+            for level in state.next.keys() {
+                if let Level::Normal(ref name) | Level::Metadata(ref name) = *level {
+                    assert!(Arc::ptr_eq(name, tree.interner.0.get_key_value(&**name).unwrap().0));
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_topic_tree() {
         let tree = TopicTree::build(vec![topic!("sport/tennis/+"),
@@ -734,6 +1568,7 @@ mod tests {
 
         assert_eq!(tree.topics.len(), 12);
         assert_eq!(tree.states.len(), 15);
+        assert_eq!(tree.interner.len(), 6);
 
         assert_eq!(tree.match_topic(&topic!("sport/tennis/player1")),
                    Some(vec![&topic!("#"),
@@ -760,4 +1595,173 @@ mod tests {
         assert_eq!(tree.match_topic(&topic!("/monitor/Clients")),
                    Some(vec![&topic!("#"), &topic!("+/monitor/Clients")]));
     }
+
+    #[test]
+    fn test_topic_tree_values() {
+        let mut tree = vec![(topic!("sport/tennis/+"), 1),
+                            (topic!("sport/tennis/player1"), 2),
+                            (topic!("sport/#"), 3)]
+            .into_iter()
+            .collect::<TopicTree<u32>>();
+
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.get(&topic!("sport/tennis/+")), Some(&1));
+        assert_eq!(tree.get(&topic!("sport/#")), Some(&3));
+        assert_eq!(tree.get(&topic!("sport/+")), None);
+
+        assert_eq!(tree.insert(&topic!("sport/#"), 4), Some(3));
+        assert_eq!(tree.insert(&topic!("sport/+"), 5), None);
+
+        *tree.get_mut(&topic!("sport/tennis/+")).unwrap() += 10;
+        *tree.add(&topic!("sport/tennis/player1")) += 20;
+        *tree.add(&topic!("#")) += 30;
+
+        let mut values = tree.matches(&topic!("sport/tennis/player1"))
+            .into_iter()
+            .map(|(topic, &value)| (topic.to_string(), value))
+            .collect::<Vec<_>>();
+
+        values.sort();
+
+        assert_eq!(values,
+                   vec![("#".to_owned(), 30),
+                        ("sport/#".to_owned(), 4),
+                        ("sport/tennis/+".to_owned(), 11),
+                        ("sport/tennis/player1".to_owned(), 22)]);
+
+        let mut entries = tree.entries().map(|(_, &value)| value).collect::<Vec<_>>();
+
+        entries.sort();
+
+        assert_eq!(entries, vec![4, 5, 11, 22, 30]);
+    }
+
+    #[test]
+    fn test_topic_tree_remove() {
+        let mut tree = TopicTree::build(vec![topic!("sport/tennis/+"),
+                                             topic!("sport/tennis/player1"),
+                                             topic!("sport/tennis/player1/#"),
+                                             topic!("sport/#"),
+                                             topic!("$SYS/monitor/+")]);
+        let states = tree.stats().states;
+
+        assert_eq!(tree.remove(&topic!("sport/tennis")), None);
+        assert_eq!(tree.remove(&topic!("sport/+")), None);
+        assert_eq!(tree.remove(&topic!("sport/tennis/player1/ranking")), None);
+
+        assert_eq!(tree.remove(&topic!("sport/tennis/player1")), Some(()));
+        assert_eq!(tree.remove(&topic!("sport/tennis/player1")), None);
+        assert_eq!(tree.len(), 4);
+        assert_eq!(tree.stats().states, states);
+        assert_eq!(tree.match_topic(&topic!("sport/tennis/player1")),
+                   Some(vec![&topic!("sport/#"),
+                             &topic!("sport/tennis/player1/#"),
+                             &topic!("sport/tennis/+")]));
+
+        assert_eq!(tree.remove(&topic!("sport/tennis/player1/#")), Some(()));
+        assert_eq!(tree.stats().states, states - 1);
+        assert_eq!(tree.stats().names, 4);
+        assert_eq!(tree.match_topic(&topic!("sport/tennis/player1")),
+                   Some(vec![&topic!("sport/#"), &topic!("sport/tennis/+")]));
+
+        assert_eq!(tree.remove(&topic!("$SYS/monitor/+")), Some(()));
+        assert_eq!(tree.stats().states, states - 4);
+        assert_eq!(tree.stats().names, 2);
+        assert!(!tree.contains(&topic!("$SYS/monitor/+")));
+
+        tree.remove(&topic!("sport/tennis/+"));
+        tree.remove(&topic!("sport/#"));
+
+        assert!(tree.is_empty());
+        assert_eq!(tree.stats().states, 1);
+        assert_eq!(tree.stats().names, 0);
+        assert_eq!(tree.match_topic(&topic!("sport/tennis/player1")), None);
+
+        tree.add(&topic!("sport/tennis/player1"));
+
+        assert_eq!(tree.match_topic(&topic!("sport/tennis/player1")),
+                   Some(vec![&topic!("sport/tennis/player1")]));
+    }
+
+    #[test]
+    fn test_topic_tree_remove_names() {
+        let mut tree = TopicTree::build(vec![topic!("a/a"), topic!("a/b")]);
+
+        assert_eq!(tree.stats().names, 2);
+
+        // a clone of a stored topic held by the caller
+        let held = tree.match_topic(&topic!("a/b")).unwrap()[0].clone();
+
+        assert_eq!(tree.remove(&topic!("a/a")), Some(()));
+        assert_eq!(tree.stats().names, 2);
+
+        assert_eq!(tree.remove(&topic!("a/b")), Some(()));
+        assert_eq!(tree.stats().names, 0);
+        assert!(tree.interner.is_empty());
+        assert_eq!(held, topic!("a/b"));
+
+        tree.add(&topic!("a/a"));
+
+        assert_eq!(tree.stats().names, 1);
+        assert!(tree.contains(&topic!("a/a")));
+    }
+
+    #[test]
+    fn test_topic_tree_introspection() {
+        let mut tree: TopicTree = TopicTree::new();
+
+        assert!(tree.is_empty());
+        assert_eq!(tree.len(), 0);
+        assert_eq!(tree.iter().next(), None);
+        assert_eq!(tree.stats(),
+                   Stats {
+                       states: 1,
+                       memory: tree.stats().memory,
+                       ..Default::default()
+                   });
+
+        for topic in &[topic!("sport/tennis/+"),
+                       topic!("sport/tennis/player1"),
+                       topic!("sport/tennis/player1/#"),
+                       topic!("sport/#"),
+                       topic!("$SYS/monitor/+"),
+                       topic!("sport/tennis/player1")] {
+            tree.add(topic);
+        }
+
+        assert!(!tree.is_empty());
+        assert_eq!(tree.len(), 5);
+
+        let mut topics = tree.iter().map(|topic| topic.to_string()).collect::<Vec<_>>();
+
+        topics.sort();
+
+        assert_eq!(topics,
+                   vec!["$SYS/monitor/+",
+                        "sport/#",
+                        "sport/tennis/+",
+                        "sport/tennis/player1",
+                        "sport/tennis/player1/#"]);
+        assert_eq!((&tree).into_iter().count(), 5);
+
+        assert!(tree.contains(&topic!("sport/tennis/+")));
+        assert!(tree.contains(&topic!("sport/tennis/player1")));
+        assert!(tree.contains(&topic!("sport/tennis/player1/#")));
+        assert!(tree.contains(&topic!("sport/#")));
+        assert!(!tree.contains(&topic!("sport")));
+        assert!(!tree.contains(&topic!("sport/tennis")));
+        assert!(!tree.contains(&topic!("sport/+")));
+        assert!(!tree.contains(&topic!("sport/tennis/player2")));
+        assert!(!tree.contains(&topic!("#")));
+
+        let stats = tree.stats();
+
+        assert_eq!(stats.topics, 5);
+        assert_eq!(stats.states, 8);
+        assert_eq!(stats.max_depth, 4);
+        assert_eq!(stats.single_wildcards, 2);
+        assert_eq!(stats.multi_wildcards, 2);
+        assert_eq!(stats.names, 5);
+        assert!(stats.memory > mem::size_of::<TopicTree>());
+    }
 }
@@ -30,6 +30,11 @@ pub trait Transport {
     fn send_packet(&mut self, _: &Packet) -> Result<()> {
         Ok(())
     }
+
+    /// Open a new connection to the same server, fails if the transport can't reconnect.
+    fn reconnect(&mut self) -> Result<()> {
+        bail!(ErrorKind::InvalidState)
+    }
 }
 
 impl Transport for Tcp {}